@@ -5,6 +5,10 @@ use facet_args as args;
 
 #[derive(Facet)]
 pub struct Opts {
+    /// Output format (text, json)
+    #[facet(default, args::named)]
+    pub format: Option<String>,
+
     #[facet(args::subcommand)]
     pub cmd: Command,
 }
@@ -42,9 +46,45 @@ pub enum Command {
         #[facet(default, args::named)]
         checksum: bool,
 
+        /// Leave checksums out, even if rustine.toml asks for them
+        #[facet(default, args::named)]
+        no_checksum: bool,
+
+        /// Checksum algorithm (sha256, blake3)
+        #[facet(default, args::named)]
+        hash: Option<String>,
+
+        /// Payload compression codec (none, lzw, zstd, xz, brotli)
+        #[facet(default, args::named)]
+        codec: Option<String>,
+
+        /// Compression level for the selected codec
+        #[facet(default, args::named)]
+        level: Option<i32>,
+
         /// Include reverse patch for bidirectional patching
         #[facet(default, args::named, args::short = 'r')]
         reverse: bool,
+
+        /// Leave the reverse patch out, even if rustine.toml asks for it
+        #[facet(default, args::named)]
+        no_reverse: bool,
+
+        /// Sign the patch with this secret key file
+        #[facet(default, args::named)]
+        sign: Option<PathBuf>,
+
+        /// Attach metadata as key=value (repeatable)
+        #[facet(default, args::named)]
+        meta: Vec<String>,
+
+        /// Attach metadata from a TOML file
+        #[facet(default, args::named)]
+        meta_file: Option<PathBuf>,
+
+        /// Patch format: rustine (default), bsdiff4, vcdiff, ips, ups, bps
+        #[facet(default, args::named)]
+        format: Option<String>,
     },
     Apply {
         #[facet(args::positional)]
@@ -79,6 +119,109 @@ pub enum Command {
         /// Verify checksums if present
         #[facet(default, args::named)]
         verify: bool,
+
+        /// Skip checksum verification, even if rustine.toml asks for it
+        #[facet(default, args::named)]
+        no_verify: bool,
+
+        /// Rewrite the base file instead of writing a separate output
+        #[facet(default, args::named)]
+        in_place: bool,
+
+        /// Refuse patches not signed by this public key file
+        #[facet(default, args::named)]
+        require_signature: Option<PathBuf>,
+
+        /// Refuse patches whose metadata does not match key=value (repeatable)
+        #[facet(default, args::named)]
+        expect_meta: Vec<String>,
+
+        /// Unchanged bytes bridged inside one change region (default 1)
+        #[facet(default, args::named)]
+        gap: Option<usize>,
+
+        /// Grow change regions to at least this many bytes
+        #[facet(default, args::named)]
+        min_region: Option<usize>,
+
+        /// Snap change regions to multiples of this many bytes, e.g. 4, 8 or 16
+        #[facet(default, args::named)]
+        align: Option<usize>,
+
+        /// Show at most this many regions in the change preview
+        #[facet(default, args::named)]
+        max_regions: Option<usize>,
+    },
+    Keygen {
+        /// Key file prefix, writes <prefix>.key and <prefix>.pub
+        #[facet(default, args::named, args::short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
+
+        /// Overwrite key files if they exist
+        #[facet(default, args::named, args::short = 'f')]
+        force: bool,
+    },
+    Verify {
+        #[facet(args::positional)]
+        base: PathBuf,
+
+        #[facet(args::positional)]
+        patch: PathBuf,
+
+        /// Expected output to compare byte for byte
+        #[facet(default, args::positional)]
+        target: Option<PathBuf>,
+
+        /// Check the reverse patch against the patched file
+        #[facet(default, args::named, args::short = 'R')]
+        reverse: bool,
+
+        /// Enable verbose output
+        #[facet(default, args::named, args::short = 'v')]
+        verbose: bool,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
+    },
+    Diff {
+        #[facet(args::positional)]
+        old: PathBuf,
+
+        #[facet(args::positional)]
+        new: PathBuf,
+
+        /// Bytes of context around each change (default 16)
+        #[facet(default, args::named, args::short = 'C')]
+        context: Option<usize>,
+
+        /// Show at most this many change regions (50 by default)
+        #[facet(default, args::named)]
+        max_regions: Option<usize>,
+
+        /// Unchanged bytes bridged inside one change region (default 1)
+        #[facet(default, args::named)]
+        gap: Option<usize>,
+
+        /// Grow change regions to at least this many bytes
+        #[facet(default, args::named)]
+        min_region: Option<usize>,
+
+        /// Snap change regions to multiples of this many bytes, e.g. 4, 8 or 16
+        #[facet(default, args::named)]
+        align: Option<usize>,
+
+        /// Print directly instead of through $PAGER
+        #[facet(default, args::named)]
+        no_pager: bool,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
     },
     Inspect {
         #[facet(args::positional)]
@@ -87,5 +230,119 @@ pub enum Command {
         /// Enable verbose output
         #[facet(default, args::named, args::short = 'v')]
         verbose: bool,
+
+        /// Decode every control triple and show statistics
+        #[facet(default, args::named)]
+        deep: bool,
+
+        /// Map the base regions the patch copies, modifies and inserts
+        #[facet(default, args::named)]
+        regions: bool,
+    },
+    Compose {
+        /// Patches in the order they apply
+        #[facet(args::positional)]
+        patches: Vec<PathBuf>,
+
+        #[facet(default, args::named, args::short = 'o')]
+        output: Option<PathBuf>,
+
+        /// First base, for chains whose control streams cannot be merged
+        #[facet(default, args::named)]
+        base: Option<PathBuf>,
+
+        /// Enable verbose output
+        #[facet(default, args::named, args::short = 'v')]
+        verbose: bool,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
+
+        /// Overwrite output file if it exists
+        #[facet(default, args::named, args::short = 'f')]
+        force: bool,
+    },
+    Upgrade {
+        #[facet(args::positional)]
+        base: PathBuf,
+
+        /// Target checksum (at least 8 hex digits) or version
+        #[facet(args::named)]
+        to: String,
+
+        /// Directory of patches to chain
+        #[facet(default, args::named)]
+        store: Option<PathBuf>,
+
+        #[facet(default, args::named, args::short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Show the chain without applying it
+        #[facet(default, args::named)]
+        dry_run: bool,
+
+        /// Enable verbose output
+        #[facet(default, args::named, args::short = 'v')]
+        verbose: bool,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
+
+        /// Overwrite output file if it exists
+        #[facet(default, args::named, args::short = 'f')]
+        force: bool,
+    },
+    Convert {
+        #[facet(args::positional)]
+        patch: PathBuf,
+
+        #[facet(default, args::named, args::short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Target format: rustine2, bsdiff4
+        #[facet(args::named)]
+        to: String,
+
+        /// Original file, to embed checksums (needs --target)
+        #[facet(default, args::named)]
+        base: Option<PathBuf>,
+
+        /// Patched file, to embed checksums (needs --base)
+        #[facet(default, args::named)]
+        target: Option<PathBuf>,
+
+        /// Checksum algorithm (sha256, blake3)
+        #[facet(default, args::named)]
+        hash: Option<String>,
+
+        /// Payload compression codec (none, lzw, zstd, xz, brotli), the input's by default
+        #[facet(default, args::named)]
+        codec: Option<String>,
+
+        /// Compression level for the selected codec
+        #[facet(default, args::named)]
+        level: Option<i32>,
+
+        /// Attach metadata as key=value (repeatable)
+        #[facet(default, args::named)]
+        meta: Vec<String>,
+
+        /// Attach metadata from a TOML file
+        #[facet(default, args::named)]
+        meta_file: Option<PathBuf>,
+
+        /// Enable verbose output
+        #[facet(default, args::named, args::short = 'v')]
+        verbose: bool,
+
+        /// Suppress all output except errors
+        #[facet(default, args::named, args::short = 'q')]
+        quiet: bool,
+
+        /// Overwrite output file if it exists
+        #[facet(default, args::named, args::short = 'f')]
+        force: bool,
     },
 }
@@ -0,0 +1,236 @@
+use std::path::{Path, PathBuf};
+
+use facet::Facet;
+use miette::{Diagnostic, SourceSpan};
+
+use crate::core::codec::{Codec, Compression};
+use crate::core::format::HashAlgorithm;
+use crate::{Level, Result, RustineError, RustineErrorContext, RustineErrorKind};
+
+/// Config file name, looked up in the working directory and the XDG config dir
+pub const FILE_NAME: &str = "rustine.toml";
+
+/// Defaults from `rustine.toml`, overridden by command-line flags
+#[derive(Facet, Debug, Default, Clone)]
+pub struct Config {
+    /// Embed checksums when generating
+    #[facet(default)]
+    pub checksum: Option<bool>,
+
+    /// Checksum algorithm (sha256, blake3)
+    #[facet(default)]
+    pub hash: Option<String>,
+
+    /// Include reverse patches when generating
+    #[facet(default)]
+    pub reverse: Option<bool>,
+
+    /// Payload compression codec
+    #[facet(default)]
+    pub codec: Option<String>,
+
+    /// Compression level for the codec
+    #[facet(default)]
+    pub compression_level: Option<i32>,
+
+    /// Verify checksums when applying
+    #[facet(default)]
+    pub verify: Option<bool>,
+
+    /// Output verbosity (quiet, normal, verbose)
+    #[facet(default)]
+    pub verbosity: Option<String>,
+
+    /// Directory for default output paths, instead of the working directory
+    #[facet(default)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Suffix of generated patches, `.patch` by default
+    #[facet(default)]
+    pub patch_suffix: Option<String>,
+
+    /// Suffix of patched files, `.patched` by default
+    #[facet(default)]
+    pub patched_suffix: Option<String>,
+
+    /// Patch store searched by `upgrade`
+    #[facet(default)]
+    pub store: Option<PathBuf>,
+}
+
+impl Config {
+    /// Load the XDG config, then the project config on top of it
+    pub fn load() -> Result<Self> {
+        let mut config = Self::default();
+        for path in Self::paths() {
+            if path.is_file() {
+                config = config.merge(Self::from_file(&path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Candidate config files, lowest precedence first
+    pub fn paths() -> Vec<PathBuf> {
+        let xdg = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+        xdg.map(|dir| dir.join("rustine").join(FILE_NAME))
+            .into_iter()
+            .chain(std::iter::once(PathBuf::from(FILE_NAME)))
+            .collect()
+    }
+
+    /// Parse a config file, pointing errors at the offending line
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            RustineError::new(
+                RustineErrorKind::from(e),
+                RustineErrorContext::default().with_path(path.to_path_buf()),
+            )
+        })?;
+        Self::parse(&text)
+            .map_err(|e| RustineError::new(e.kind, e.ctx.with_path(path.to_path_buf())))
+    }
+
+    /// Parse config TOML
+    pub fn parse(text: &str) -> Result<Self> {
+        let config = facet_toml::from_str::<Self>(text).map_err(|e| {
+            let span = e
+                .labels()
+                .and_then(|mut labels| labels.next())
+                .map(|label| *label.inner());
+            let mut ctx = RustineErrorContext::default().with_contents(text);
+            if let Some(span) = span {
+                ctx = ctx.with_span(span);
+            }
+            RustineError::new(
+                RustineErrorKind::Config {
+                    src: Box::new(miette::MietteDiagnostic::new(e.to_string())),
+                },
+                ctx,
+            )
+        })?;
+
+        // Catch bad values here rather than on first use, pointing at them
+        let at = |key: &'static str| {
+            move |e: RustineError| {
+                let mut ctx = RustineErrorContext::default().with_contents(text);
+                if let Some(span) = value_span(text, key) {
+                    ctx = ctx.with_span(span);
+                }
+                RustineError::new(e.kind, ctx)
+            }
+        };
+        if let Some(ref verbosity) = config.verbosity {
+            parse_verbosity(verbosity).map_err(at("verbosity"))?;
+        }
+        if let Some(ref hash) = config.hash {
+            hash.parse::<HashAlgorithm>().map_err(at("hash"))?;
+        }
+        let codec = config
+            .codec
+            .as_deref()
+            .map(str::parse::<Codec>)
+            .transpose()
+            .map_err(at("codec"))?;
+        if let Some(level) = config.compression_level {
+            Compression::new(codec.unwrap_or_default(), Some(level))
+                .map_err(at("compression_level"))?;
+        }
+        Ok(config)
+    }
+
+    /// Fields set in `other` win
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            checksum: other.checksum.or(self.checksum),
+            hash: other.hash.or(self.hash),
+            reverse: other.reverse.or(self.reverse),
+            // A level only carries over with the codec it was set for
+            compression_level: match other.codec {
+                Some(_) => other.compression_level,
+                None => other.compression_level.or(self.compression_level),
+            },
+            codec: other.codec.or(self.codec),
+            verify: other.verify.or(self.verify),
+            verbosity: other.verbosity.or(self.verbosity),
+            output_dir: other.output_dir.or(self.output_dir),
+            patch_suffix: other.patch_suffix.or(self.patch_suffix),
+            patched_suffix: other.patched_suffix.or(self.patched_suffix),
+            store: other.store.or(self.store),
+        }
+    }
+
+    /// Output level from flags, falling back to the configured verbosity
+    pub fn level(&self, verbose: bool, quiet: bool) -> Level {
+        if verbose || quiet {
+            return Level::from_flags(verbose, quiet);
+        }
+        self.verbosity
+            .as_deref()
+            .and_then(|v| parse_verbosity(v).ok())
+            .unwrap_or(Level::Normal)
+    }
+
+    /// Suffix of generated patches
+    pub fn patch_suffix(&self) -> &str {
+        self.patch_suffix.as_deref().unwrap_or(".patch")
+    }
+
+    /// Suffix of patched files
+    pub fn patched_suffix(&self) -> &str {
+        self.patched_suffix.as_deref().unwrap_or(".patched")
+    }
+}
+
+fn parse_verbosity(verbosity: &str) -> Result<Level> {
+    match verbosity.to_ascii_lowercase().as_str() {
+        "quiet" => Ok(Level::Quiet),
+        "normal" => Ok(Level::Normal),
+        "verbose" => Ok(Level::Verbose),
+        _ => Err(RustineErrorKind::Config {
+            src: Box::new(miette::MietteDiagnostic::new(format!(
+                "unknown verbosity `{}`, expected quiet, normal or verbose",
+                verbosity
+            ))),
+        }
+        .into()),
+    }
+}
+
+/// Span of a top-level key's value, up to the end of its line
+fn value_span(text: &str, key: &str) -> Option<SourceSpan> {
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(rest) = line[indent..].strip_prefix(key)
+            && rest.trim_start().starts_with('=')
+        {
+            let value = &line[line.find('=')? + 1..];
+            let start = line.len() - value.trim_start().len();
+            let len = value.trim().len();
+            return Some((offset + start, len).into());
+        }
+        offset += line.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_point_at_values() {
+        let text = "codec = \"zstd\"\n  compression_level=99 \nhash = \"md5\"\n";
+        let span = |key| value_span(text, key).map(|span| &text[span.offset()..][..span.len()]);
+        assert_eq!(span("codec"), Some("\"zstd\""));
+        assert_eq!(span("compression_level"), Some("99"));
+        assert_eq!(span("hash"), Some("\"md5\""));
+        assert_eq!(span("compression"), None);
+        assert_eq!(span("verbosity"), None);
+    }
+}
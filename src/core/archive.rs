@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Write};
+use std::path::{Component, Path};
+
+use facet::Facet;
+
+use crate::{Result, RustineErrorKind};
+
+use super::codec::{Codec, Compression};
+use super::format::{HashAlgorithm, PatchFormat};
+use super::stream::{check_bounds, read_array, read_exact};
+
+/// Size constants
+const HASH_SIZE: usize = 32;
+const U32_SIZE: usize = 4;
+const U64_SIZE: usize = 8;
+
+/// Checksum flags per entry
+const ENTRY_BASE_CHECKSUM: u8 = 1 << 0;
+const ENTRY_OUTPUT_CHECKSUM: u8 = 1 << 1;
+
+/// What happened to a file between the two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Same contents at the same path
+    Unchanged,
+    /// Contents changed, payload is a bsdiff patch
+    Modified,
+    /// New file, payload is its contents
+    Added,
+    /// File no longer exists
+    Removed,
+    /// Same contents moved from another path
+    Renamed { from: String },
+}
+
+impl EntryKind {
+    fn id(&self) -> u8 {
+        match self {
+            Self::Unchanged => 0,
+            Self::Modified => 1,
+            Self::Added => 2,
+            Self::Removed => 3,
+            Self::Renamed { .. } => 4,
+        }
+    }
+
+    /// Get kind name as string
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unchanged => "unchanged",
+            Self::Modified => "modified",
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Renamed { .. } => "renamed",
+        }
+    }
+}
+
+/// One file of a directory archive
+///
+/// Paths are relative and `/`-separated. Payloads are encoded with the
+/// archive's compression.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    pub path: String,
+    pub kind: EntryKind,
+    pub base_checksum: Option<[u8; 32]>,
+    pub output_checksum: Option<[u8; 32]>,
+    pub output_size: u64,
+    pub payload: &'a [u8],
+}
+
+/// Settings shared by every entry of an archive
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArchiveHeader {
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub entry_count: u32,
+}
+
+/// Entry counts per kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Facet)]
+pub struct ArchiveSummary {
+    pub unchanged: usize,
+    pub modified: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub renamed: usize,
+}
+
+impl std::fmt::Display for ArchiveSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} modified, {} added, {} removed, {} renamed, {} unchanged",
+            self.modified, self.added, self.removed, self.renamed, self.unchanged
+        )
+    }
+}
+
+/// Decide what each file becomes, from per-path checksums of both trees
+///
+/// A removed file whose contents reappear under a new path is a rename.
+pub fn plan(
+    base: &BTreeMap<String, [u8; 32]>,
+    target: &BTreeMap<String, [u8; 32]>,
+) -> Vec<(String, EntryKind)> {
+    // Removed files by contents, candidates for renames
+    let mut gone: HashMap<[u8; 32], Vec<&String>> = HashMap::new();
+    for (path, hash) in base {
+        if !target.contains_key(path) {
+            gone.entry(*hash).or_default().push(path);
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (path, hash) in target {
+        let kind = match base.get(path) {
+            Some(base_hash) if base_hash == hash => EntryKind::Unchanged,
+            Some(_) => EntryKind::Modified,
+            None => match gone.get_mut(hash).and_then(|paths| paths.pop()) {
+                Some(from) => EntryKind::Renamed { from: from.clone() },
+                None => EntryKind::Added,
+            },
+        };
+        entries.push((path.clone(), kind));
+    }
+
+    // Whatever was not claimed by a rename is gone for good
+    let mut removed: Vec<&String> = gone.into_values().flatten().collect();
+    removed.sort();
+    entries.extend(
+        removed
+            .into_iter()
+            .map(|path| (path.clone(), EntryKind::Removed)),
+    );
+
+    entries
+}
+
+/// Ensure an archive path stays inside the tree it is applied to
+pub fn validate_path(path: &str) -> Result<()> {
+    let valid = !path.is_empty()
+        && !path.contains('\\')
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+
+    if !valid {
+        return Err(RustineErrorKind::CorruptedPatch {
+            details: format!("unsafe entry path `{}`", path),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Streaming archive serializer
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    remaining: u32,
+    written: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Write the archive header
+    pub fn new(mut inner: W, header: &ArchiveHeader) -> Result<Self> {
+        inner.write_all(PatchFormat::Archive.magic().unwrap())?;
+        inner.write_all(&[PatchFormat::Archive.version().unwrap()])?;
+        inner.write_all(&[header.hash_algorithm.id()])?;
+        inner.write_all(&[header.compression.codec.id()])?;
+        inner.write_all(&header.compression.level.to_le_bytes())?;
+        inner.write_all(&header.entry_count.to_le_bytes())?;
+
+        Ok(Self {
+            inner,
+            remaining: header.entry_count,
+            written: (8 + 1 + 1 + 1 + U32_SIZE + U32_SIZE) as u64,
+        })
+    }
+
+    /// Write one entry
+    pub fn write_entry(&mut self, entry: &Entry<'_>) -> Result<()> {
+        if self.remaining == 0 {
+            return Err(misuse("more entries than announced in the header"));
+        }
+
+        let from = match &entry.kind {
+            EntryKind::Renamed { from } => from.as_str(),
+            _ => "",
+        };
+        let mut flags = 0u8;
+        if entry.base_checksum.is_some() {
+            flags |= ENTRY_BASE_CHECKSUM;
+        }
+        if entry.output_checksum.is_some() {
+            flags |= ENTRY_OUTPUT_CHECKSUM;
+        }
+
+        self.inner.write_all(&[entry.kind.id(), flags])?;
+        self.write_str(&entry.path)?;
+        self.write_str(from)?;
+        self.written += 2;
+
+        // Write optional checksums
+        for hash in [entry.base_checksum, entry.output_checksum]
+            .into_iter()
+            .flatten()
+        {
+            self.inner.write_all(&hash)?;
+            self.written += HASH_SIZE as u64;
+        }
+
+        self.inner.write_all(&entry.output_size.to_le_bytes())?;
+        self.inner
+            .write_all(&(entry.payload.len() as u64).to_le_bytes())?;
+        self.inner.write_all(entry.payload)?;
+        self.written += (2 * U64_SIZE + entry.payload.len()) as u64;
+
+        self.remaining -= 1;
+        Ok(())
+    }
+
+    /// Flush and return total bytes written
+    pub fn finish(mut self) -> Result<u64> {
+        if self.remaining != 0 {
+            return Err(misuse("fewer entries than announced in the header"));
+        }
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<()> {
+        self.inner.write_all(&(s.len() as u32).to_le_bytes())?;
+        self.inner.write_all(s.as_bytes())?;
+        self.written += (U32_SIZE + s.len()) as u64;
+        Ok(())
+    }
+}
+
+/// Borrowed view of a directory archive
+#[derive(Debug, Clone)]
+pub struct ArchiveView<'a> {
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub entries: Vec<Entry<'a>>,
+}
+
+impl<'a> ArchiveView<'a> {
+    /// Parse archive, borrowing payloads from bytes
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if PatchFormat::detect(data) != PatchFormat::Archive {
+            return Err(RustineErrorKind::FormatMismatch {
+                details: "this is a single-file patch, not a directory archive".to_string(),
+            }
+            .into());
+        }
+
+        let size = data.len() as u64;
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(8);
+
+        let [version] = read_array::<1, _>(&mut cursor, "version")?;
+        if version != PatchFormat::Archive.version().unwrap() {
+            return Err(RustineErrorKind::UnsupportedVersion { version }.into());
+        }
+
+        let [hash_id] = read_array::<1, _>(&mut cursor, "hash algorithm")?;
+        let [codec_id] = read_array::<1, _>(&mut cursor, "codec")?;
+        let level = i32::from_le_bytes(read_array::<U32_SIZE, _>(&mut cursor, "codec level")?);
+        let entry_count =
+            u32::from_le_bytes(read_array::<U32_SIZE, _>(&mut cursor, "entry count")?);
+
+        let mut entries = Vec::new();
+        for _ in 0..entry_count {
+            let [kind_id, flags] = read_array::<2, _>(&mut cursor, "entry kind")?;
+            let path = read_string(&mut cursor, size, "entry path")?;
+            let from = read_string(&mut cursor, size, "entry source path")?;
+            validate_path(&path)?;
+
+            let kind = match kind_id {
+                0 => EntryKind::Unchanged,
+                1 => EntryKind::Modified,
+                2 => EntryKind::Added,
+                3 => EntryKind::Removed,
+                4 => {
+                    validate_path(&from)?;
+                    EntryKind::Renamed { from }
+                }
+                _ => {
+                    return Err(RustineErrorKind::CorruptedPatch {
+                        details: format!("unknown entry kind {}", kind_id),
+                    }
+                    .into());
+                }
+            };
+
+            // Read optional checksums
+            let base_checksum = if flags & ENTRY_BASE_CHECKSUM != 0 {
+                Some(read_array::<HASH_SIZE, _>(
+                    &mut cursor,
+                    "entry base checksum",
+                )?)
+            } else {
+                None
+            };
+            let output_checksum = if flags & ENTRY_OUTPUT_CHECKSUM != 0 {
+                Some(read_array::<HASH_SIZE, _>(
+                    &mut cursor,
+                    "entry output checksum",
+                )?)
+            } else {
+                None
+            };
+
+            let output_size =
+                u64::from_le_bytes(read_array::<U64_SIZE, _>(&mut cursor, "entry size")?);
+            let payload_len = u64::from_le_bytes(read_array::<U64_SIZE, _>(
+                &mut cursor,
+                "entry payload length",
+            )?);
+            let start = cursor.position();
+            check_bounds(start, payload_len, size, "entry payload")?;
+            cursor.set_position(start + payload_len);
+
+            entries.push(Entry {
+                path,
+                kind,
+                base_checksum,
+                output_checksum,
+                output_size,
+                payload: &data[start as usize..(start + payload_len) as usize],
+            });
+        }
+
+        Ok(Self {
+            hash_algorithm: HashAlgorithm::from_id(hash_id)?,
+            compression: Compression {
+                codec: Codec::from_id(codec_id)?,
+                level,
+            },
+            entries,
+        })
+    }
+
+    /// Count entries per kind
+    pub fn summary(&self) -> ArchiveSummary {
+        let mut summary = ArchiveSummary::default();
+        for entry in &self.entries {
+            match entry.kind {
+                EntryKind::Unchanged => summary.unchanged += 1,
+                EntryKind::Modified => summary.modified += 1,
+                EntryKind::Added => summary.added += 1,
+                EntryKind::Removed => summary.removed += 1,
+                EntryKind::Renamed { .. } => summary.renamed += 1,
+            }
+        }
+        summary
+    }
+
+    /// Total size of the rebuilt tree
+    pub fn output_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| e.kind != EntryKind::Removed)
+            .map(|e| e.output_size)
+            .sum()
+    }
+}
+
+/// Helper to read a length-prefixed UTF-8 string
+fn read_string(cursor: &mut Cursor<&[u8]>, size: u64, field_name: &str) -> Result<String> {
+    let len = u32::from_le_bytes(read_array::<U32_SIZE, _>(cursor, field_name)?) as u64;
+    check_bounds(cursor.position(), len, size, field_name)?;
+
+    let mut bytes = vec![0u8; len as usize];
+    read_exact(cursor, &mut bytes, field_name)?;
+    String::from_utf8(bytes).map_err(|_| {
+        RustineErrorKind::CorruptedPatch {
+            details: format!("{} is not valid UTF-8", field_name),
+        }
+        .into()
+    })
+}
+
+fn misuse(details: &str) -> crate::RustineError {
+    RustineErrorKind::from(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        details.to_string(),
+    ))
+    .into()
+}
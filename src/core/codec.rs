@@ -0,0 +1,208 @@
+use std::borrow::Cow;
+use std::io::Read;
+
+use crate::{Result, RustineErrorKind};
+
+/// Brotli window size (log2)
+const BROTLI_LGWIN: u32 = 22;
+/// Internal buffer size for brotli streams
+const BROTLI_BUFFER: usize = 4096;
+/// LZW symbol size in bits
+const LZW_CODE_SIZE: u8 = 8;
+
+/// Compression codecs for patch payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Stored as emitted by bsdiff
+    #[default]
+    None,
+    /// LZW (weezl)
+    Lzw,
+    /// Zstandard
+    Zstd,
+    /// XZ / LZMA2
+    Xz,
+    /// Brotli
+    Brotli,
+}
+
+impl Codec {
+    /// Get codec id as stored in the header
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lzw => 1,
+            Self::Zstd => 2,
+            Self::Xz => 3,
+            Self::Brotli => 4,
+        }
+    }
+
+    /// Get codec from header id
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lzw),
+            2 => Ok(Self::Zstd),
+            3 => Ok(Self::Xz),
+            4 => Ok(Self::Brotli),
+            _ => Err(RustineErrorKind::UnsupportedCodec {
+                name: format!("#{}", id),
+            }
+            .into()),
+        }
+    }
+
+    /// Get codec name as string
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lzw => "lzw",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+            Self::Brotli => "brotli",
+        }
+    }
+
+    /// Accepted compression levels
+    pub fn levels(&self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Self::None | Self::Lzw => 0..=0,
+            Self::Zstd => 1..=22,
+            Self::Xz => 0..=9,
+            Self::Brotli => 0..=11,
+        }
+    }
+
+    /// Level used when none is given
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Self::None | Self::Lzw => 0,
+            Self::Zstd => 3,
+            Self::Xz => 6,
+            Self::Brotli => 9,
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = crate::RustineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lzw" => Ok(Self::Lzw),
+            "zstd" => Ok(Self::Zstd),
+            "xz" | "lzma" => Ok(Self::Xz),
+            "brotli" => Ok(Self::Brotli),
+            _ => Err(RustineErrorKind::UnsupportedCodec {
+                name: s.to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Codec and level applied to every payload of a patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+impl Compression {
+    /// Create settings, using the codec default when no level is given
+    pub fn new(codec: Codec, level: Option<i32>) -> Result<Self> {
+        let level = level.unwrap_or_else(|| codec.default_level());
+        let levels = codec.levels();
+        if !levels.contains(&level) {
+            return Err(RustineErrorKind::InvalidLevel {
+                codec: codec.name().to_string(),
+                level,
+                min: *levels.start(),
+                max: *levels.end(),
+            }
+            .into());
+        }
+        Ok(Self { codec, level })
+    }
+
+    /// Whether payloads are stored as is
+    pub fn is_none(&self) -> bool {
+        self.codec == Codec::None
+    }
+
+    /// Compress a payload, borrowing it when stored as is
+    pub fn compress<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let encode_failed = |source| RustineErrorKind::DiffFailed { source };
+
+        let out = match self.codec {
+            Codec::None => return Ok(Cow::Borrowed(data)),
+            Codec::Lzw => weezl::encode::Encoder::new(weezl::BitOrder::Msb, LZW_CODE_SIZE)
+                .encode(data)
+                .map_err(|e| encode_failed(std::io::Error::other(e)))?,
+            Codec::Zstd => zstd::encode_all(data, self.level).map_err(encode_failed)?,
+            Codec::Xz => {
+                let mut out = Vec::new();
+                xz2::read::XzEncoder::new(data, self.level as u32)
+                    .read_to_end(&mut out)
+                    .map_err(encode_failed)?;
+                out
+            }
+            Codec::Brotli => {
+                let mut out = Vec::new();
+                brotli::CompressorReader::new(data, BROTLI_BUFFER, self.level as u32, BROTLI_LGWIN)
+                    .read_to_end(&mut out)
+                    .map_err(encode_failed)?;
+                out
+            }
+        };
+        Ok(Cow::Owned(out))
+    }
+
+    /// Compress an owned payload, handing it back when stored as is
+    pub fn compress_vec(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_none() {
+            return Ok(data);
+        }
+        self.compress(&data).map(Cow::into_owned)
+    }
+
+    /// Decompress a payload, borrowing it when stored as is
+    pub fn decompress<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let decode_failed = |source| RustineErrorKind::InvalidPatch { source };
+
+        let out = match self.codec {
+            Codec::None => return Ok(Cow::Borrowed(data)),
+            Codec::Lzw => weezl::decode::Decoder::new(weezl::BitOrder::Msb, LZW_CODE_SIZE)
+                .decode(data)
+                .map_err(|e| decode_failed(std::io::Error::other(e)))?,
+            Codec::Zstd => zstd::decode_all(data).map_err(decode_failed)?,
+            Codec::Xz => {
+                let mut out = Vec::new();
+                xz2::read::XzDecoder::new(data)
+                    .read_to_end(&mut out)
+                    .map_err(decode_failed)?;
+                out
+            }
+            Codec::Brotli => {
+                let mut out = Vec::new();
+                brotli::Decompressor::new(data, BROTLI_BUFFER)
+                    .read_to_end(&mut out)
+                    .map_err(decode_failed)?;
+                out
+            }
+        };
+        Ok(Cow::Owned(out))
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.codec.levels().count() > 1 {
+            write!(f, "{} (level {})", self.codec.name(), self.level)
+        } else {
+            write!(f, "{}", self.codec.name())
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use crate::{Result, RustineErrorKind};
+
+use super::control::{Control, ControlStream, Segment};
+use super::format::{HashAlgorithm, PatchData, PatchFormat, PatchView};
+use super::meta;
+
+/// A patch chain merged into one patch
+#[derive(Debug)]
+pub struct Composed {
+    pub data: Vec<u8>,
+    /// Whether the control streams were merged, or the output rebuilt from the base
+    pub rebuilt: bool,
+    pub has_reverse: bool,
+    /// Parts of the chain the composed patch could not keep
+    pub lost: Vec<&'static str>,
+}
+
+/// Merge patches applied one after the other into a single RUSTINE2 patch
+///
+/// Bsdiff control streams are merged without the intermediate files. When a
+/// patch is not bsdiff or reads past the end of its base, the chain is
+/// applied to `base` in memory and diffed instead. Without `base`, a reverse
+/// chain that cannot be merged, or an output checksum of another algorithm
+/// than the base checksum, is dropped and listed in [`Composed::lost`].
+pub fn compose(patches: &[&[u8]], base: Option<&[u8]>) -> Result<Composed> {
+    let views = patches
+        .iter()
+        .map(|data| match PatchFormat::detect(data) {
+            PatchFormat::Archive => Err(RustineErrorKind::FormatMismatch {
+                details: "directory archives cannot be composed".to_string(),
+            }
+            .into()),
+            _ => PatchView::parse(data),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (Some(first), Some(last)) = (views.first(), views.last()) else {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "nothing to compose, give at least one patch".to_string(),
+        }
+        .into());
+    };
+
+    // Neighbours that both carry checksums must meet on the same file
+    for (index, pair) in views.windows(2).enumerate() {
+        if let (Some(output), Some(base)) = (pair[0].output_checksum, pair[1].base_checksum)
+            && pair[0].hash_algorithm == pair[1].hash_algorithm
+            && output != base
+        {
+            return Err(RustineErrorKind::InvalidArguments {
+                details: format!(
+                    "patch {} does not start from the output of patch {}",
+                    index + 2,
+                    index + 1
+                ),
+            }
+            .into());
+        }
+    }
+
+    let mut patch = PatchData::new(Vec::new());
+    patch.compression = first.compression;
+    patch.metadata = compose_metadata(first, last)?;
+
+    let bsdiff = views
+        .iter()
+        .all(|view| matches!(view.format, PatchFormat::Rustine2 | PatchFormat::Bsdiff4));
+    let merged = if bsdiff {
+        merge_chain(views.iter().map(|view| (view, view.forward_patch)))?
+    } else {
+        None
+    };
+    let rebuilt = merged.is_none();
+    let mut lost = Vec::new();
+
+    match merged {
+        Some(forward) => {
+            // Reverse patches run from the last output back to the first base
+            let reverse = views
+                .iter()
+                .rev()
+                .map(|view| view.reverse_patch.map(|reverse| (view, reverse)))
+                .collect::<Option<Vec<_>>>();
+            let merged_reverse = match reverse {
+                Some(reverse) => Some(merge_chain(reverse.into_iter())?),
+                None => None,
+            };
+
+            // Checksums of both ends of the chain
+            let algorithm = match first.base_checksum {
+                Some(_) => first.hash_algorithm,
+                None => last.hash_algorithm,
+            };
+            let rehash = last.output_checksum.is_some() && last.hash_algorithm != algorithm;
+
+            // Fill what the patches alone cannot give from the output, if the base is at hand
+            let output = match base {
+                Some(base) if rehash || matches!(merged_reverse, Some(None)) => {
+                    Some((base, apply_chain(&views, base)?))
+                }
+                _ => None,
+            };
+
+            patch.reverse_patch = match (merged_reverse, &output) {
+                (Some(Some(reverse)), _) => Some(reverse),
+                (Some(None), Some((base, output))) => Some(super::diff::create(output, base)?),
+                (Some(None), None) => {
+                    lost.push("reverse patch");
+                    None
+                }
+                (None, _) => None,
+            }
+            .map(|reverse| patch.compression.compress_vec(reverse))
+            .transpose()?;
+
+            patch.hash_algorithm = algorithm;
+            patch.base_checksum = first.base_checksum;
+            patch.output_checksum = match (&output, rehash) {
+                (_, false) => last.output_checksum,
+                (Some((_, output)), true) => Some(algorithm.digest(output)),
+                (None, true) => {
+                    lost.push("output checksum");
+                    None
+                }
+            };
+            patch.forward_patch = patch.compression.compress_vec(forward)?;
+        }
+        None => {
+            let Some(base) = base else {
+                return Err(RustineErrorKind::FormatMismatch {
+                    details: "these patches cannot be merged without their files, \
+                              pass the first base with --base"
+                        .to_string(),
+                }
+                .into());
+            };
+            let output = apply_chain(&views, base)?;
+            patch.forward_patch = patch
+                .compression
+                .compress_vec(super::diff::create(base, &output)?)?;
+            if views.iter().all(|view| view.reverse_patch.is_some()) {
+                patch.reverse_patch = Some(
+                    patch
+                        .compression
+                        .compress_vec(super::diff::create(&output, base)?)?,
+                );
+            }
+
+            // The files are at hand, so both ends are hashed afresh
+            if first.base_checksum.is_some() || last.output_checksum.is_some() {
+                let algorithm = match first.base_checksum {
+                    Some(_) => first.hash_algorithm,
+                    None => last.hash_algorithm,
+                };
+                let algorithm = match algorithm {
+                    HashAlgorithm::Legacy => HashAlgorithm::default(),
+                    algorithm => algorithm,
+                };
+                patch = patch.with_checksums(
+                    algorithm,
+                    algorithm.digest(base),
+                    algorithm.digest(&output),
+                );
+            }
+        }
+    }
+
+    Ok(Composed {
+        has_reverse: patch.reverse_patch.is_some(),
+        data: patch.serialize(),
+        rebuilt,
+        lost,
+    })
+}
+
+/// Merge a chain of compressed bsdiff payloads into a raw one, `None` if one reads past its base
+fn merge_chain<'a>(
+    chain: impl Iterator<Item = (&'a PatchView<'a>, &'a [u8])>,
+) -> Result<Option<Vec<u8>>> {
+    let mut merged: Option<ControlStream> = None;
+    for (view, payload) in chain {
+        let stream = ControlStream::decode(&view.compression.decompress(payload)?)?;
+        merged = match merged {
+            None => Some(stream),
+            Some(previous) => match merge(&previous, &stream) {
+                Some(stream) => Some(stream),
+                None => return Ok(None),
+            },
+        };
+    }
+    merged.map(|stream| stream.encode()).transpose()
+}
+
+/// Merge `first` (A→B) and `second` (B→C) into one A→C control stream
+///
+/// Every byte of C is a byte of B plus a diff byte, or literal. Bytes of B
+/// are in turn bytes of A plus a diff byte, or literal, so the diff bytes
+/// add up and A is never needed. `None` when `second` reads outside B.
+pub fn merge(first: &ControlStream, second: &ControlStream) -> Option<ControlStream> {
+    let segments: Vec<Segment> = first.segments().collect();
+    let mut out = StreamBuilder::default();
+
+    for segment in second.segments() {
+        match segment {
+            Segment::Copy {
+                extra_offset, len, ..
+            } => {
+                let start = extra_offset as usize;
+                out.literal(&second.extra[start..start + len as usize]);
+            }
+            Segment::Add {
+                base_offset,
+                diff_offset,
+                len,
+                ..
+            } => {
+                if base_offset < 0 || base_offset as u64 + len > first.new_size {
+                    return None;
+                }
+                let mut at = base_offset as u64;
+                let end = at + len;
+                let mut diff = &second.diff[diff_offset as usize..(diff_offset + len) as usize];
+
+                // Split at the segments of `first` that built these bytes of B
+                let mut index = segments.partition_point(|s| s.output_offset() + s.len() <= at);
+                while at < end {
+                    let source = segments[index];
+                    let skip = at - source.output_offset();
+                    let take = (source.len() - skip).min(end - at);
+                    let (head, rest) = diff.split_at(take as usize);
+                    match source {
+                        Segment::Add {
+                            base_offset,
+                            diff_offset,
+                            ..
+                        } => {
+                            let start = (diff_offset + skip) as usize;
+                            let bytes = sum(&first.diff[start..start + take as usize], head);
+                            out.add(base_offset + skip as i64, &bytes);
+                        }
+                        Segment::Copy { extra_offset, .. } => {
+                            let start = (extra_offset + skip) as usize;
+                            out.literal(&sum(&first.extra[start..start + take as usize], head));
+                        }
+                    }
+                    diff = rest;
+                    at += take;
+                    index += 1;
+                }
+            }
+        }
+    }
+
+    Some(out.finish(second.new_size))
+}
+
+/// Bytewise wrapping sum, as bspatch adds diff bytes
+fn sum(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a.wrapping_add(*b)).collect()
+}
+
+/// Control stream assembled from base reads and literals in output order
+#[derive(Default)]
+struct StreamBuilder {
+    controls: Vec<Control>,
+    diff: Vec<u8>,
+    extra: Vec<u8>,
+    /// Base position after the last add
+    cursor: i64,
+}
+
+impl StreamBuilder {
+    fn add(&mut self, base: i64, diff: &[u8]) {
+        let cursor = self.cursor;
+        let last = self.last();
+        if last.copy == 0 && cursor == base {
+            // Contiguous with the previous add, extend it
+            last.add += diff.len() as u64;
+        } else {
+            last.seek = base - cursor;
+            self.controls.push(Control {
+                add: diff.len() as u64,
+                ..Default::default()
+            });
+        }
+        self.cursor = base + diff.len() as i64;
+        self.diff.extend_from_slice(diff);
+    }
+
+    fn literal(&mut self, bytes: &[u8]) {
+        self.last().copy += bytes.len() as u64;
+        self.extra.extend_from_slice(bytes);
+    }
+
+    /// Triple being filled, an empty one reading from base offset 0 at first
+    fn last(&mut self) -> &mut Control {
+        if self.controls.is_empty() {
+            self.controls.push(Control::default());
+        }
+        self.controls.last_mut().expect("just pushed")
+    }
+
+    fn finish(self, new_size: u64) -> ControlStream {
+        ControlStream {
+            new_size,
+            controls: self.controls,
+            diff: self.diff,
+            extra: self.extra,
+        }
+    }
+}
+
+/// Apply every patch to `base` in turn, checking the checksums along the way
+fn apply_chain(views: &[PatchView], base: &[u8]) -> Result<Vec<u8>> {
+    let mut data = base.to_vec();
+    for view in views {
+        if let Some(expected) = &view.base_checksum {
+            super::format::verify_hash(view.hash_algorithm, &data, expected)?;
+        }
+        let payload = view.compression.decompress(view.forward_patch)?;
+        data = super::patch::apply_as(view.format, &data, &payload)?;
+        if let Some(expected) = &view.output_checksum {
+            super::format::verify_hash(view.hash_algorithm, &data, expected)?;
+        }
+    }
+    Ok(data)
+}
+
+/// Last patch's metadata, starting from the first patch's source version
+fn compose_metadata(first: &PatchView, last: &PatchView) -> Result<Option<String>> {
+    let source = first.meta()?.source_version().map(str::to_string);
+    let mut meta = last.meta()?;
+    if let Some(source) = source {
+        meta.set(meta::SOURCE_VERSION, source);
+    }
+    if meta.is_empty() {
+        return Ok(None);
+    }
+    meta.to_toml().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::sample;
+    use crate::core::{diff, patch};
+
+    fn apply(base: &[u8], stream: &ControlStream) -> Vec<u8> {
+        patch::apply(base, &stream.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn merges_generated_patches() {
+        let a = sample(1, 4096);
+        let mut b = a.clone();
+        b[100..110].fill(0xee);
+        b.splice(2000..2000, sample(7, 300));
+        let mut c = b.clone();
+        c.drain(500..900);
+        c[3000] ^= 0x55;
+
+        let first = ControlStream::decode(&diff::create(&a, &b).unwrap()).unwrap();
+        let second = ControlStream::decode(&diff::create(&b, &c).unwrap()).unwrap();
+        let merged = merge(&first, &second).unwrap();
+        assert_eq!(apply(&a, &merged), c);
+    }
+
+    #[test]
+    fn merges_literals_and_backward_seeks() {
+        let a = sample(3, 64);
+        // B: a[0..16], 8 literal bytes, then a[8..40] after seeking back
+        let first = ControlStream {
+            new_size: 56,
+            controls: vec![
+                Control {
+                    add: 16,
+                    copy: 8,
+                    seek: -8,
+                },
+                Control {
+                    add: 32,
+                    copy: 0,
+                    seek: 0,
+                },
+            ],
+            diff: (0..48).map(|i| (i % 3) as u8).collect(),
+            extra: sample(9, 8),
+        };
+        let b = apply(&a, &first);
+
+        // C: B bytes across the literal run, then a seek back to the start of B
+        let second = ControlStream {
+            new_size: 44,
+            controls: vec![
+                Control {
+                    add: 0,
+                    copy: 0,
+                    seek: 10,
+                },
+                Control {
+                    add: 20,
+                    copy: 4,
+                    seek: -30,
+                },
+                Control {
+                    add: 20,
+                    copy: 0,
+                    seek: 0,
+                },
+            ],
+            diff: (0..40).map(|i| (i % 5) as u8).collect(),
+            extra: sample(11, 4),
+        };
+        let c = apply(&b, &second);
+
+        let merged = merge(&first, &second).unwrap();
+        assert_eq!(apply(&a, &merged), c);
+    }
+
+    #[test]
+    fn reports_checksums_it_cannot_carry() {
+        let a = sample(5, 256);
+        let mut b = a.clone();
+        b[10] ^= 1;
+        let mut c = b.clone();
+        c[200] ^= 1;
+        let wrap = |base: &[u8], target: &[u8], algorithm: HashAlgorithm| {
+            PatchData::new(diff::create(base, target).unwrap())
+                .with_checksums(algorithm, algorithm.digest(base), algorithm.digest(target))
+                .serialize()
+        };
+        let first = wrap(&a, &b, HashAlgorithm::Sha256);
+        let second = wrap(&b, &c, HashAlgorithm::Blake3);
+
+        let composed = compose(&[&first, &second], None).unwrap();
+        assert_eq!(composed.lost, ["output checksum"]);
+
+        let composed = compose(&[&first, &second], Some(&a)).unwrap();
+        assert!(composed.lost.is_empty());
+        let patch = PatchData::deserialize(&composed.data).unwrap();
+        assert_eq!(patch.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(
+            patch.output_checksum,
+            Some(HashAlgorithm::Sha256.digest(&c))
+        );
+    }
+
+    #[test]
+    fn refuses_reads_past_the_intermediate_file() {
+        let first = ControlStream {
+            new_size: 4,
+            controls: vec![Control {
+                add: 0,
+                copy: 4,
+                seek: 0,
+            }],
+            diff: Vec::new(),
+            extra: vec![1; 4],
+        };
+        let second = ControlStream {
+            new_size: 8,
+            controls: vec![Control {
+                add: 8,
+                copy: 0,
+                seek: 0,
+            }],
+            diff: vec![0; 8],
+            extra: Vec::new(),
+        };
+        assert!(merge(&first, &second).is_none());
+    }
+}
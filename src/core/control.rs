@@ -0,0 +1,248 @@
+use std::io::{Read, Write};
+
+use crate::{Result, RustineErrorKind};
+
+/// BSDIFF40 magic
+pub const MAGIC: &[u8; 8] = b"BSDIFF40";
+/// Magic + control length + diff length + new size
+const HEADER_SIZE: usize = 32;
+/// Three offsets per control triple
+const CONTROL_SIZE: usize = 24;
+
+/// One control triple of a bsdiff patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Control {
+    /// Bytes built from the base plus diff bytes
+    pub add: u64,
+    /// Literal bytes taken from the extra block
+    pub copy: u64,
+    /// Base cursor move after the triple
+    pub seek: i64,
+}
+
+/// Piece of the output, with absolute offsets into base, output and blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    /// `len` base bytes at `base_offset`, plus diff bytes at `diff_offset`
+    Add {
+        output_offset: u64,
+        base_offset: i64,
+        diff_offset: u64,
+        len: u64,
+    },
+    /// `len` literal bytes at `extra_offset`
+    Copy {
+        output_offset: u64,
+        extra_offset: u64,
+        len: u64,
+    },
+}
+
+/// Decoded BSDIFF40 patch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlStream {
+    pub new_size: u64,
+    pub controls: Vec<Control>,
+    pub diff: Vec<u8>,
+    pub extra: Vec<u8>,
+}
+
+impl ControlStream {
+    /// Decode a raw BSDIFF40 patch
+    pub fn decode(patch: &[u8]) -> Result<Self> {
+        if patch.len() < HEADER_SIZE || !patch.starts_with(MAGIC) {
+            return Err(invalid("missing BSDIFF40 header"));
+        }
+
+        let control_len = read_offset(&patch[8..16]);
+        let diff_len = read_offset(&patch[16..24]);
+        let new_size = read_offset(&patch[24..32]);
+        if control_len < 0 || diff_len < 0 || new_size < 0 {
+            return Err(invalid("negative length in header"));
+        }
+
+        let control_end = (HEADER_SIZE as u64).saturating_add(control_len as u64);
+        let diff_end = control_end.saturating_add(diff_len as u64);
+        if diff_end > patch.len() as u64 {
+            return Err(invalid("blocks extend past end of patch"));
+        }
+        let (control_end, diff_end) = (control_end as usize, diff_end as usize);
+
+        let control_block = unpack(&patch[HEADER_SIZE..control_end])?;
+        let diff = unpack(&patch[control_end..diff_end])?;
+        let extra = unpack(&patch[diff_end..])?;
+
+        if control_block.len() % CONTROL_SIZE != 0 {
+            return Err(invalid("control block is not made of whole triples"));
+        }
+
+        let mut controls = Vec::with_capacity(control_block.len() / CONTROL_SIZE);
+        for triple in control_block.chunks_exact(CONTROL_SIZE) {
+            let add = read_offset(&triple[0..8]);
+            let copy = read_offset(&triple[8..16]);
+            if add < 0 || copy < 0 {
+                return Err(invalid("negative length in control triple"));
+            }
+            controls.push(Control {
+                add: add as u64,
+                copy: copy as u64,
+                seek: read_offset(&triple[16..24]),
+            });
+        }
+
+        let stream = Self {
+            new_size: new_size as u64,
+            controls,
+            diff,
+            extra,
+        };
+        stream.validate()?;
+        Ok(stream)
+    }
+
+    /// Encode as a raw BSDIFF40 patch
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        self.validate()?;
+
+        let mut control_block = Vec::with_capacity(self.controls.len() * CONTROL_SIZE);
+        for control in &self.controls {
+            control_block.extend_from_slice(&write_offset(control.add as i64));
+            control_block.extend_from_slice(&write_offset(control.copy as i64));
+            control_block.extend_from_slice(&write_offset(control.seek));
+        }
+
+        let control_block = pack(&control_block)?;
+        let diff = pack(&self.diff)?;
+        let extra = pack(&self.extra)?;
+
+        let mut out =
+            Vec::with_capacity(HEADER_SIZE + control_block.len() + diff.len() + extra.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&write_offset(control_block.len() as i64));
+        out.extend_from_slice(&write_offset(diff.len() as i64));
+        out.extend_from_slice(&write_offset(self.new_size as i64));
+        out.extend_from_slice(&control_block);
+        out.extend_from_slice(&diff);
+        out.extend_from_slice(&extra);
+        Ok(out)
+    }
+
+    /// Walk the control triples as absolute segments
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        let (mut output, mut base, mut diff, mut extra) = (0u64, 0i64, 0u64, 0u64);
+
+        self.controls.iter().flat_map(move |control| {
+            let add = Segment::Add {
+                output_offset: output,
+                base_offset: base,
+                diff_offset: diff,
+                len: control.add,
+            };
+            output += control.add;
+            diff += control.add;
+            base = base
+                .saturating_add(control.add as i64)
+                .saturating_add(control.seek);
+
+            let copy = Segment::Copy {
+                output_offset: output,
+                extra_offset: extra,
+                len: control.copy,
+            };
+            output += control.copy;
+            extra += control.copy;
+
+            [add, copy]
+                .into_iter()
+                .filter(|segment| !segment.is_empty())
+        })
+    }
+
+    /// Ensure block sizes agree with the control triples
+    fn validate(&self) -> Result<()> {
+        // Lengths come from the patch, so a crafted one may overflow
+        let (mut add, mut copy) = (0u64, 0u64);
+        for control in &self.controls {
+            add = add
+                .checked_add(control.add)
+                .ok_or_else(|| invalid("control triples overflow"))?;
+            copy = copy
+                .checked_add(control.copy)
+                .ok_or_else(|| invalid("control triples overflow"))?;
+        }
+
+        if add != self.diff.len() as u64 {
+            return Err(invalid("diff block does not match control triples"));
+        }
+        if copy != self.extra.len() as u64 {
+            return Err(invalid("extra block does not match control triples"));
+        }
+        if add.checked_add(copy) != Some(self.new_size) {
+            return Err(invalid("control triples do not add up to the new size"));
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    /// Output offset of the segment
+    pub fn output_offset(&self) -> u64 {
+        match *self {
+            Self::Add { output_offset, .. } | Self::Copy { output_offset, .. } => output_offset,
+        }
+    }
+
+    /// Number of output bytes
+    pub fn len(&self) -> u64 {
+        match *self {
+            Self::Add { len, .. } | Self::Copy { len, .. } => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Read a sign-magnitude offset
+fn read_offset(bytes: &[u8]) -> i64 {
+    let raw = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let magnitude = (raw & !(1 << 63)) as i64;
+    if raw >> 63 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Write a sign-magnitude offset
+fn write_offset(value: i64) -> [u8; 8] {
+    let mut raw = value.unsigned_abs();
+    if value < 0 {
+        raw |= 1 << 63;
+    }
+    raw.to_le_bytes()
+}
+
+fn unpack(block: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    bzip2::read::BzDecoder::new(block)
+        .read_to_end(&mut out)
+        .map_err(|source| RustineErrorKind::InvalidPatch { source })?;
+    Ok(out)
+}
+
+fn pack(block: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+    encoder
+        .write_all(block)
+        .and_then(|_| encoder.finish())
+        .map_err(|source| RustineErrorKind::DiffFailed { source }.into())
+}
+
+fn invalid(details: &str) -> crate::RustineError {
+    RustineErrorKind::InvalidPatch {
+        source: std::io::Error::new(std::io::ErrorKind::InvalidData, details.to_string()),
+    }
+    .into()
+}
@@ -0,0 +1,114 @@
+use crate::{Result, RustineErrorKind};
+
+use super::codec::Compression;
+use super::format::{HashAlgorithm, PatchData, PatchFormat, PatchView};
+use super::meta::Metadata;
+
+/// What to add while rewrapping a patch
+#[derive(Debug, Default)]
+pub struct ConvertOptions<'a> {
+    /// Payload compression of the new envelope, the input's when `None`
+    pub compression: Option<Compression>,
+    /// Base and target the patch connects, to embed their checksums
+    pub files: Option<(&'a [u8], &'a [u8])>,
+    pub hash: HashAlgorithm,
+    /// Merged over the input's metadata, keys given here win
+    pub metadata: Option<Metadata>,
+}
+
+/// A rewrapped patch and what did not survive the conversion
+#[derive(Debug)]
+pub struct Converted {
+    pub from: PatchFormat,
+    pub data: Vec<u8>,
+    /// Features of the input the output cannot carry
+    pub lost: Vec<&'static str>,
+}
+
+/// Rewrap a RUSTINE2 or raw BSDIFF4 patch as `to`, without the original files
+pub fn convert(data: &[u8], to: PatchFormat, options: ConvertOptions) -> Result<Converted> {
+    let from = PatchFormat::detect(data);
+    if !matches!(from, PatchFormat::Rustine2 | PatchFormat::Bsdiff4) {
+        return Err(RustineErrorKind::FormatMismatch {
+            details: format!(
+                "only RUSTINE2 and BSDIFF4 patches can be rewrapped, {} has no bsdiff payload",
+                from.name()
+            ),
+        }
+        .into());
+    }
+    if !matches!(to, PatchFormat::Rustine2 | PatchFormat::Bsdiff4) {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: format!(
+                "cannot convert to {}, expected rustine2 or bsdiff4",
+                to.name()
+            ),
+        }
+        .into());
+    }
+
+    // The envelope is rebuilt, so an existing signature never carries over
+    let signed = PatchView::parse(data)?.signature.is_some();
+    let mut patch = PatchData::deserialize(data)?;
+    let forward = patch
+        .compression
+        .decompress(&patch.forward_patch)?
+        .into_owned();
+    let reverse = match &patch.reverse_patch {
+        Some(reverse) => Some(patch.compression.decompress(reverse)?.into_owned()),
+        None => None,
+    };
+
+    if let Some((base, target)) = options.files {
+        // Only embed checksums of files this patch really connects
+        if super::patch::apply(base, &forward)? != target {
+            return Err(RustineErrorKind::InvalidArguments {
+                details: "the patch does not turn the given base into the given target".to_string(),
+            }
+            .into());
+        }
+        patch = patch.with_checksums(
+            options.hash,
+            options.hash.digest(base),
+            options.hash.digest(target),
+        );
+    }
+
+    if let Some(extra) = options.metadata {
+        let mut meta = patch.meta()?;
+        for (key, value) in extra.iter() {
+            meta.set(key, value);
+        }
+        patch = patch.with_metadata(meta.to_toml()?);
+    }
+
+    let mut lost = Vec::new();
+    if signed {
+        lost.push("signature");
+    }
+    let data = match to {
+        PatchFormat::Bsdiff4 => {
+            if patch.base_checksum.is_some() || patch.output_checksum.is_some() {
+                lost.push("checksums");
+            }
+            if patch.reverse_patch.is_some() {
+                lost.push("reverse patch");
+            }
+            if patch.metadata.is_some() {
+                lost.push("metadata");
+            }
+            forward
+        }
+        _ => {
+            let compression = options.compression.unwrap_or(patch.compression);
+            patch.forward_patch = compression.compress_vec(forward)?;
+            patch.reverse_patch = reverse
+                .map(|reverse| compression.compress_vec(reverse))
+                .transpose()?;
+            patch.compression = compression;
+            patch.serialize()
+        }
+    };
+
+    Ok(Converted { from, data, lost })
+}
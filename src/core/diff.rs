@@ -2,6 +2,11 @@ use std::io::Write;
 
 use crate::{Result, RustineErrorKind};
 
+use super::control::{ControlStream, Segment};
+use super::format::PatchFormat;
+use super::rom;
+use super::vcdiff::{self, AddressCache, Inst};
+
 /// Generate binary diff/patch
 pub fn create(base: &[u8], target: &[u8]) -> Result<Vec<u8>> {
     let mut out = Vec::new();
@@ -23,3 +28,394 @@ pub fn write_to<W: Write>(base: &[u8], target: &[u8], writer: &mut W) -> Result<
         .compare(writer)
         .map_err(|e| RustineErrorKind::DiffFailed { source: e }.into())
 }
+
+/// Offset + size of an IPS record
+const IPS_RECORD_HEADER: usize = 5;
+/// IPS offset that reads as the "EOF" marker
+const EOF_OFFSET: usize = 0x454f46;
+/// BPS action kinds
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+const BPS_SOURCE_COPY: u64 = 2;
+
+/// Target bytes covered by one VCDIFF window
+const VCDIFF_WINDOW: u64 = 8 << 20;
+/// Shortest run of unchanged bytes worth a copy instead of literal bytes
+const MIN_COPY: u64 = 4;
+
+/// Generate a patch of the given raw format
+pub fn create_as(format: PatchFormat, base: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        PatchFormat::Bsdiff4 => create(base, target),
+        PatchFormat::Vcdiff => create_vcdiff(base, target),
+        PatchFormat::Ips => create_ips(base, target),
+        PatchFormat::Ups => Ok(create_ups(base, target)),
+        PatchFormat::Bps => create_bps(base, target),
+        _ => Err(RustineErrorKind::FormatMismatch {
+            details: format!("{} is not a raw delta format", format.name()),
+        }
+        .into()),
+    }
+}
+
+/// Generate a VCDIFF (RFC 3284) patch
+///
+/// Matches come from bsdiff: unchanged runs become COPY instructions from
+/// the base, everything else ADD. No secondary compression or checksums, so
+/// any RFC 3284 decoder can read it.
+pub fn create_vcdiff(base: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    let stream = ControlStream::decode(&create(base, target)?)?;
+    let ops = delta_ops(&stream);
+
+    let mut out = Vec::new();
+    vcdiff::write_header(&mut out);
+
+    // Split ops straddling a window end
+    let mut windows: Vec<Vec<DeltaOp>> = Vec::new();
+    for mut op in ops {
+        while op.len > 0 {
+            let window = (op.output / VCDIFF_WINDOW) as usize;
+            let len = op.len.min((window as u64 + 1) * VCDIFF_WINDOW - op.output);
+            if windows.len() <= window {
+                windows.resize_with(window + 1, Vec::new);
+            }
+            windows[window].push(DeltaOp { len, ..op });
+            op = DeltaOp {
+                output: op.output + len,
+                len: op.len - len,
+                base: op.base.map(|base| base + len),
+            };
+        }
+    }
+
+    for (index, ops) in windows.iter().enumerate() {
+        encode_window(&mut out, ops, index as u64 * VCDIFF_WINDOW, target);
+    }
+
+    Ok(out)
+}
+
+/// Output range, copied from `base` when set, literal otherwise
+#[derive(Debug, Clone, Copy)]
+struct DeltaOp {
+    output: u64,
+    len: u64,
+    base: Option<u64>,
+}
+
+/// Turn bsdiff segments into base copies and literal ranges, merging neighbouring literals
+fn delta_ops(stream: &ControlStream) -> Vec<DeltaOp> {
+    let mut ops: Vec<DeltaOp> = Vec::new();
+    let mut push = |op: DeltaOp| match ops.last_mut() {
+        Some(last) if last.base.is_none() && op.base.is_none() => last.len += op.len,
+        _ => ops.push(op),
+    };
+
+    for segment in stream.segments() {
+        let (output, len) = (segment.output_offset(), segment.len());
+        let Segment::Add {
+            base_offset,
+            diff_offset,
+            ..
+        } = segment
+        else {
+            push(DeltaOp {
+                output,
+                len,
+                base: None,
+            });
+            continue;
+        };
+
+        // Runs of zero diff bytes are unchanged base bytes
+        let diff = &stream.diff[diff_offset as usize..(diff_offset + len) as usize];
+        let mut at = 0;
+        while at < len {
+            let unchanged = diff[at as usize] == 0;
+            let run = diff[at as usize..]
+                .iter()
+                .take_while(|byte| (**byte == 0) == unchanged)
+                .count() as u64;
+            let copy = unchanged && run >= MIN_COPY;
+            push(DeltaOp {
+                output: output + at,
+                len: run,
+                base: copy.then(|| (base_offset + at as i64) as u64),
+            });
+            at += run;
+        }
+    }
+
+    ops
+}
+
+/// Append one window whose source segment spans all its copies
+fn encode_window(out: &mut Vec<u8>, ops: &[DeltaOp], window_start: u64, target: &[u8]) {
+    let copies = ops
+        .iter()
+        .filter_map(|op| op.base.map(|base| base..base + op.len));
+    let segment = copies
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+        .unwrap_or(0..0);
+    let segment_len = segment.end - segment.start;
+
+    let mut data = Vec::new();
+    let mut instructions = Vec::new();
+    let mut addresses = Vec::new();
+    let mut cache = AddressCache::new();
+
+    // An instruction waits here in case it can share a code with the next
+    let mut pending: Option<(Inst, u64, u8)> = None;
+    for op in ops {
+        let current = match op.base {
+            Some(base) => {
+                let here = segment_len + op.output - window_start;
+                let mode = cache.encode(base - segment.start, here, &mut addresses);
+                (Inst::Copy, op.len, mode)
+            }
+            None => {
+                data.extend_from_slice(&target[op.output as usize..(op.output + op.len) as usize]);
+                (Inst::Add, op.len, 0)
+            }
+        };
+
+        let paired = pending.and_then(|(inst, size, mode)| match (inst, current.0) {
+            (Inst::Add, Inst::Copy) => vcdiff::add_copy_code(size, current.1, current.2),
+            (Inst::Copy, Inst::Add) => vcdiff::copy_add_code(size, mode, current.1),
+            _ => None,
+        });
+        match paired {
+            Some(code) => {
+                instructions.push(code);
+                pending = None;
+            }
+            None => {
+                if let Some(pending) = pending {
+                    push_instruction(&mut instructions, pending);
+                }
+                pending = Some(current);
+            }
+        }
+    }
+    if let Some(pending) = pending {
+        push_instruction(&mut instructions, pending);
+    }
+
+    let target_len = ops.iter().map(|op| op.len).sum();
+    let source = (segment_len > 0).then_some((segment_len, segment.start));
+    vcdiff::write_window(out, source, target_len, &data, &instructions, &addresses);
+}
+
+/// Append a lone instruction and its size if the code table lacks it
+fn push_instruction(instructions: &mut Vec<u8>, (inst, size, mode): (Inst, u64, u8)) {
+    let (code, explicit) = match inst {
+        Inst::Copy => vcdiff::copy_code(size, mode),
+        _ => vcdiff::add_code(size),
+    };
+    instructions.push(code);
+    if explicit {
+        vcdiff::write_varint(instructions, size);
+    }
+}
+
+/// Generate an IPS patch
+///
+/// IPS only overwrites bytes at fixed offsets, so nothing is matched. A
+/// shorter target is cut with the truncation extension.
+pub fn create_ips(base: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    if target.len() as u64 > rom::IPS_MAX_OFFSET {
+        return Err(RustineErrorKind::DiffFailed {
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "IPS cannot address files over 16 MiB",
+            ),
+        }
+        .into());
+    }
+
+    let mut out = rom::IPS_MAGIC.to_vec();
+    let differs = |i: usize| base.get(i) != Some(&target[i]);
+    let mut at = 0;
+    while at < target.len() {
+        if !differs(at) {
+            at += 1;
+            continue;
+        }
+
+        // Bridge unchanged gaps shorter than a record header, leaving one
+        // byte of room to step back from the "EOF" offset
+        let mut end = at + 1;
+        let mut last = at;
+        while end < target.len()
+            && end - at < rom::IPS_MAX_RECORD - 1
+            && end - last <= IPS_RECORD_HEADER
+        {
+            if differs(end) {
+                last = end;
+            }
+            end += 1;
+        }
+        let mut start = at;
+        let end = last + 1;
+
+        // An offset spelling "EOF" would end the patch early
+        if start == EOF_OFFSET {
+            start -= 1;
+        }
+
+        let bytes = &target[start..end];
+        out.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        if bytes.len() > IPS_RECORD_HEADER && bytes.iter().all(|&byte| byte == bytes[0]) {
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            out.push(bytes[0]);
+        } else {
+            out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+        at = end;
+    }
+
+    out.extend_from_slice(b"EOF");
+    if target.len() < base.len() {
+        out.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(out)
+}
+
+/// Generate a UPS patch: XOR runs between the two files, usable both ways
+pub fn create_ups(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = rom::UPS_MAGIC.to_vec();
+    rom::write_number(&mut out, base.len() as u64);
+    rom::write_number(&mut out, target.len() as u64);
+
+    let size = base.len().max(target.len());
+    let xor = |i: usize| base.get(i).unwrap_or(&0) ^ target.get(i).unwrap_or(&0);
+    let mut position = 0;
+    let mut at = 0;
+    while at < size {
+        if xor(at) == 0 {
+            at += 1;
+            continue;
+        }
+        rom::write_number(&mut out, (at - position) as u64);
+        while at < size && xor(at) != 0 {
+            out.push(xor(at));
+            at += 1;
+        }
+        out.push(0);
+        at += 1;
+        position = at;
+    }
+
+    rom::write_footer(&mut out, base, target);
+    out
+}
+
+/// Generate a BPS patch
+///
+/// Matches come from bsdiff: unchanged runs in place become source reads,
+/// moved ones source copies, everything else literal target reads.
+pub fn create_bps(base: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    let stream = ControlStream::decode(&create(base, target)?)?;
+
+    let mut out = rom::BPS_MAGIC.to_vec();
+    rom::write_number(&mut out, base.len() as u64);
+    rom::write_number(&mut out, target.len() as u64);
+    rom::write_number(&mut out, 0);
+
+    let mut source = 0u64;
+    for op in delta_ops(&stream) {
+        let action =
+            |out: &mut Vec<u8>, kind: u64| rom::write_number(out, ((op.len - 1) << 2) | kind);
+        match op.base {
+            Some(base) if base == op.output => action(&mut out, BPS_SOURCE_READ),
+            Some(base) => {
+                action(&mut out, BPS_SOURCE_COPY);
+                rom::write_offset(&mut out, base as i64 - source as i64);
+                source = base + op.len;
+            }
+            None => {
+                action(&mut out, BPS_TARGET_READ);
+                out.extend_from_slice(&target[op.output as usize..(op.output + op.len) as usize]);
+            }
+        }
+    }
+
+    rom::write_footer(&mut out, base, target);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::{edited, rotated, sample};
+    use crate::core::patch;
+
+    /// Base and target pairs covering edits, moves, growth and shrinking
+    fn pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let base = sample(1, 8192);
+
+        vec![
+            (base.clone(), edited(&base)),
+            (base.clone(), rotated(&base)),
+            (base.clone(), base[1000..5000].to_vec()),
+            (base.clone(), Vec::new()),
+            (Vec::new(), base.clone()),
+            (base.clone(), base),
+        ]
+    }
+
+    #[test]
+    fn vcdiff_roundtrip() {
+        for (base, target) in pairs() {
+            let patch = create_vcdiff(&base, &target).unwrap();
+            assert_eq!(patch::apply_vcdiff(&base, &patch).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn ips_roundtrip() {
+        for (base, target) in pairs() {
+            let patch = create_ips(&base, &target).unwrap();
+            assert_eq!(patch::apply_ips(&base, &patch).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn ips_steps_back_from_eof_offset() {
+        let base = vec![0; EOF_OFFSET + 16];
+        let mut target = base.clone();
+        target[EOF_OFFSET] = 1;
+        target[EOF_OFFSET + 8] = 2;
+
+        let patch = create_ips(&base, &target).unwrap();
+        assert_eq!(patch::apply_ips(&base, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_roundtrip_both_ways() {
+        for (base, target) in pairs() {
+            let patch = create_ups(&base, &target);
+            assert_eq!(patch::apply_ups(&base, &patch).unwrap(), target);
+            assert_eq!(patch::apply_ups(&target, &patch).unwrap(), base);
+        }
+    }
+
+    #[test]
+    fn bps_roundtrip() {
+        for (base, target) in pairs() {
+            let patch = create_bps(&base, &target).unwrap();
+            assert_eq!(patch::apply_bps(&base, &patch).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn bps_rejects_another_base() {
+        let (base, target) = pairs().remove(0);
+        let patch = create_bps(&base, &target).unwrap();
+        let mut other = base.clone();
+        other[0] ^= 1;
+        assert!(patch::apply_bps(&other, &patch).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+/// Deterministic filler bytes, different for every seed
+pub fn sample(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// `base` (at least 6001 bytes) with an overwritten run, an insertion and a flipped byte
+pub fn edited(base: &[u8]) -> Vec<u8> {
+    let mut edited = base.to_vec();
+    edited[100..140].fill(0xee);
+    edited.splice(3000..3000, sample(7, 500));
+    edited[6000] ^= 0x55;
+    edited
+}
+
+/// `base` with its halves swapped, so every write clobbers a read
+pub fn rotated(base: &[u8]) -> Vec<u8> {
+    let mut target = base[base.len() / 2..].to_vec();
+    target.extend_from_slice(&base[..base.len() / 2]);
+    target
+}
@@ -1,10 +1,12 @@
+use std::io::{Cursor, Write};
+use std::ops::Range;
+
 use crate::{Result, RustineErrorKind};
 
-/// Size constants
-const HASH_SIZE: usize = 32;
-const U32_SIZE: usize = 4;
-const U64_SIZE: usize = 8;
-const RUSTINE2_HEADER_SIZE: usize = 13; // magic(8) + version(1) + flags(4)
+use super::codec::Compression;
+use super::meta::Metadata;
+use super::sign::Signature;
+use super::stream::{PatchHeader, PatchReader, PatchWriter};
 
 /// Patch format types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -13,6 +15,16 @@ pub enum PatchFormat {
     Rustine2,
     /// Raw BSDIFF4 patch
     Bsdiff4,
+    /// Directory archive of per-file entries
+    Archive,
+    /// Raw VCDIFF (RFC 3284) delta, as written by xdelta3 and open-vcdiff
+    Vcdiff,
+    /// IPS ROM patch: offset records, no checksums
+    Ips,
+    /// UPS ROM patch: XOR runs with CRC32 checks
+    Ups,
+    /// BPS ROM patch: copy actions with CRC32 checks
+    Bps,
 }
 
 impl PatchFormat {
@@ -20,12 +32,31 @@ impl PatchFormat {
     const RUSTINE2_MAGIC: &'static [u8; 8] = b"RUSTINE2";
 
     /// Current RUSTINE2 version
-    const RUSTINE2_VERSION: u8 = 2;
+    const RUSTINE2_VERSION: u8 = 3;
+
+    /// RUSTINE2 version without a hash algorithm byte (legacy checksums)
+    pub(crate) const RUSTINE2_LEGACY_VERSION: u8 = 2;
+
+    /// Magic bytes for directory archives
+    const ARCHIVE_MAGIC: &'static [u8; 8] = b"RUSTARC1";
+
+    /// Current archive version
+    const ARCHIVE_VERSION: u8 = 1;
 
     /// Detect format from patch data
     pub fn detect(data: &[u8]) -> Self {
         if data.len() >= 8 && &data[0..8] == Self::RUSTINE2_MAGIC {
             Self::Rustine2
+        } else if data.len() >= 8 && &data[0..8] == Self::ARCHIVE_MAGIC {
+            Self::Archive
+        } else if data.starts_with(super::vcdiff::MAGIC) {
+            Self::Vcdiff
+        } else if data.starts_with(super::rom::IPS_MAGIC) {
+            Self::Ips
+        } else if data.starts_with(super::rom::UPS_MAGIC) {
+            Self::Ups
+        } else if data.starts_with(super::rom::BPS_MAGIC) {
+            Self::Bps
         } else {
             Self::Bsdiff4
         }
@@ -35,7 +66,8 @@ impl PatchFormat {
     pub fn magic(&self) -> Option<&'static [u8; 8]> {
         match self {
             Self::Rustine2 => Some(Self::RUSTINE2_MAGIC),
-            Self::Bsdiff4 => None,
+            Self::Bsdiff4 | Self::Vcdiff | Self::Ips | Self::Ups | Self::Bps => None,
+            Self::Archive => Some(Self::ARCHIVE_MAGIC),
         }
     }
 
@@ -43,7 +75,8 @@ impl PatchFormat {
     pub fn version(&self) -> Option<u8> {
         match self {
             Self::Rustine2 => Some(Self::RUSTINE2_VERSION),
-            Self::Bsdiff4 => None,
+            Self::Bsdiff4 | Self::Vcdiff | Self::Ips | Self::Ups | Self::Bps => None,
+            Self::Archive => Some(Self::ARCHIVE_VERSION),
         }
     }
 
@@ -52,6 +85,43 @@ impl PatchFormat {
         match self {
             Self::Rustine2 => "RUSTINE2",
             Self::Bsdiff4 => "BSDIFF4",
+            Self::Archive => "RUSTARC1",
+            Self::Vcdiff => "VCDIFF",
+            Self::Ips => "IPS",
+            Self::Ups => "UPS",
+            Self::Bps => "BPS",
+        }
+    }
+
+    /// Format of other tools, without a RUSTINE2 header for checksums, reverse or metadata
+    pub fn is_foreign(&self) -> bool {
+        !matches!(self, Self::Rustine2 | Self::Archive)
+    }
+
+    /// IPS, UPS or BPS
+    pub fn is_rom(&self) -> bool {
+        matches!(self, Self::Ips | Self::Ups | Self::Bps)
+    }
+}
+
+impl std::str::FromStr for PatchFormat {
+    type Err = crate::RustineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rustine" | "rustine2" => Ok(Self::Rustine2),
+            "bsdiff" | "bsdiff4" => Ok(Self::Bsdiff4),
+            "vcdiff" => Ok(Self::Vcdiff),
+            "ips" => Ok(Self::Ips),
+            "ups" => Ok(Self::Ups),
+            "bps" => Ok(Self::Bps),
+            _ => Err(RustineErrorKind::InvalidArguments {
+                details: format!(
+                    "unknown patch format `{}`, expected rustine, bsdiff4, vcdiff, ips, ups or bps",
+                    s
+                ),
+            }
+            .into()),
         }
     }
 }
@@ -61,10 +131,140 @@ pub const FLAG_BASE_CHECKSUM: u32 = 1 << 0; // 0x00000001
 pub const FLAG_OUTPUT_CHECKSUM: u32 = 1 << 1; // 0x00000002
 pub const FLAG_REVERSE_PATCH: u32 = 1 << 2; // 0x00000004
 pub const FLAG_METADATA: u32 = 1 << 3; // 0x00000008
+pub const FLAG_COMPRESSION: u32 = 1 << 4; // 0x00000010
+pub const FLAG_SIGNATURE: u32 = 1 << 5; // 0x00000020
+
+/// Checksum algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    /// 64-bit `DefaultHasher` zero-padded to 32 bytes (RUSTINE2 version 2 only)
+    Legacy,
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// BLAKE3
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Get algorithm id as stored in the header
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Legacy => 0,
+            Self::Sha256 => 1,
+            Self::Blake3 => 2,
+        }
+    }
+
+    /// Get algorithm from header id
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::Legacy),
+            1 => Ok(Self::Sha256),
+            2 => Ok(Self::Blake3),
+            _ => Err(RustineErrorKind::UnknownHashAlgorithm {
+                name: format!("#{}", id),
+            }
+            .into()),
+        }
+    }
+
+    /// Get algorithm name as string
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy hash",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Compute digest of data
+    pub fn digest(&self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Self::Legacy => legacy_hash(data),
+            Self::Sha256 => {
+                use sha2::Digest;
+                sha2::Sha256::digest(data).into()
+            }
+            Self::Blake3 => blake3::hash(data).into(),
+        }
+    }
+
+    /// Start an incremental digest of `len` bytes
+    ///
+    /// The legacy hash covers the length first, so it must be known up front.
+    pub fn hasher(&self, len: u64) -> StreamHasher {
+        match self {
+            Self::Legacy => {
+                use std::hash::Hasher;
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                hasher.write_usize(len as usize);
+                StreamHasher::Legacy(hasher)
+            }
+            Self::Sha256 => {
+                use sha2::Digest;
+                StreamHasher::Sha256(sha2::Sha256::new())
+            }
+            Self::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Incremental digest, equal to [`HashAlgorithm::digest`] of everything fed to it
+pub enum StreamHasher {
+    Legacy(std::collections::hash_map::DefaultHasher),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl StreamHasher {
+    /// Feed more data
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Legacy(hasher) => std::hash::Hasher::write(hasher, data),
+            Self::Sha256(hasher) => sha2::Digest::update(hasher, data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Finish the digest
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            Self::Legacy(hasher) => {
+                let mut result = [0u8; 32];
+                result[0..8].copy_from_slice(&std::hash::Hasher::finish(&hasher).to_le_bytes());
+                result
+            }
+            Self::Sha256(hasher) => sha2::Digest::finalize(hasher).into(),
+            Self::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = crate::RustineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(RustineErrorKind::UnknownHashAlgorithm {
+                name: s.to_string(),
+            }
+            .into()),
+        }
+    }
+}
 
 /// Patch data with all optional features
+///
+/// Payloads are stored encoded with `compression`.
 #[derive(Debug)]
 pub struct PatchData {
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
     pub base_checksum: Option<[u8; 32]>,
     pub output_checksum: Option<[u8; 32]>,
     pub forward_patch: Vec<u8>,
@@ -76,6 +276,8 @@ impl PatchData {
     /// Create a new patch with just forward data
     pub fn new(forward_patch: Vec<u8>) -> Self {
         Self {
+            hash_algorithm: HashAlgorithm::default(),
+            compression: Compression::default(),
             base_checksum: None,
             output_checksum: None,
             forward_patch,
@@ -84,8 +286,14 @@ impl PatchData {
         }
     }
 
-    /// Add checksums
-    pub fn with_checksums(mut self, base: [u8; 32], output: [u8; 32]) -> Self {
+    /// Add checksums computed with the given algorithm
+    pub fn with_checksums(
+        mut self,
+        algorithm: HashAlgorithm,
+        base: [u8; 32],
+        output: [u8; 32],
+    ) -> Self {
+        self.hash_algorithm = algorithm;
         self.base_checksum = Some(base);
         self.output_checksum = Some(output);
         self
@@ -103,202 +311,133 @@ impl PatchData {
         self
     }
 
-    /// Serialize to bytes
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut flags = 0u32;
-        let mut size = 13; // magic(8) + version(1) + flags(4)
-
-        // Calculate size and set flags
-        if self.base_checksum.is_some() {
-            flags |= FLAG_BASE_CHECKSUM;
-            size += 32;
-        }
-        if self.output_checksum.is_some() {
-            flags |= FLAG_OUTPUT_CHECKSUM;
-            size += 32;
-        }
-        if let Some(meta) = &self.metadata {
-            flags |= FLAG_METADATA;
-            size += 4 + meta.len();
-        }
-        size += 8 + self.forward_patch.len(); // forward_len(8) + data
-        if let Some(rev) = &self.reverse_patch {
-            flags |= FLAG_REVERSE_PATCH;
-            size += 8 + rev.len(); // reverse_len(8) + data
-        }
-
-        let mut data = Vec::with_capacity(size);
-
-        // Write header
-        data.extend_from_slice(PatchFormat::Rustine2.magic().unwrap());
-        data.push(PatchFormat::Rustine2.version().unwrap());
-        data.extend_from_slice(&flags.to_le_bytes());
+    /// Parse metadata (empty when absent)
+    pub fn meta(&self) -> Result<Metadata> {
+        self.metadata
+            .as_deref()
+            .map_or_else(|| Ok(Metadata::default()), Metadata::parse)
+    }
 
-        // Write optional checksums
-        if let Some(hash) = self.base_checksum {
-            data.extend_from_slice(&hash);
-        }
-        if let Some(hash) = self.output_checksum {
-            data.extend_from_slice(&hash);
+    /// Get the header describing this patch
+    pub fn header(&self) -> PatchHeader {
+        PatchHeader {
+            hash_algorithm: self.hash_algorithm,
+            compression: self.compression,
+            base_checksum: self.base_checksum,
+            output_checksum: self.output_checksum,
+            metadata: self.metadata.clone(),
+            has_reverse: self.reverse_patch.is_some(),
+            signature: None,
         }
+    }
 
-        // Write optional metadata
-        if let Some(meta) = &self.metadata {
-            data.extend_from_slice(&(meta.len() as u32).to_le_bytes());
-            data.extend_from_slice(meta.as_bytes());
-        }
+    /// Serialize to bytes
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.serialized_size() as usize);
+        self.write_to(&mut data)
+            .expect("writing to a Vec cannot fail");
+        data
+    }
 
-        // Write forward patch
-        data.extend_from_slice(&(self.forward_patch.len() as u64).to_le_bytes());
-        data.extend_from_slice(&self.forward_patch);
+    /// Serialized size in bytes
+    pub fn serialized_size(&self) -> u64 {
+        self.header().serialized_size()
+            + 8
+            + self.forward_patch.len() as u64
+            + self
+                .reverse_patch
+                .as_ref()
+                .map_or(0, |rev| 8 + rev.len() as u64)
+    }
 
-        // Write reverse patch if present
+    /// Serialize to writer, return bytes written
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut writer = PatchWriter::new(writer, &self.header())?;
+        writer.write_forward(
+            self.forward_patch.len() as u64,
+            self.forward_patch.as_slice(),
+        )?;
         if let Some(rev) = &self.reverse_patch {
-            data.extend_from_slice(&(rev.len() as u64).to_le_bytes());
-            data.extend_from_slice(rev);
+            writer.write_reverse(rev.len() as u64, rev.as_slice())?;
         }
-
-        data
+        writer.finish()
     }
 
     /// Deserialize from bytes
     pub fn deserialize(data: &[u8]) -> Result<Self> {
-        let format = PatchFormat::detect(data);
-
-        match format {
-            PatchFormat::Rustine2 => {
-                if data.len() < RUSTINE2_HEADER_SIZE {
-                    return Err(RustineErrorKind::CorruptedPatch {
-                        details: format!(
-                            "file too small ({} bytes, expected at least {})",
-                            data.len(),
-                            RUSTINE2_HEADER_SIZE
-                        ),
-                    }
-                    .into());
-                }
-
-                let version = data[8];
-                if version != PatchFormat::Rustine2.version().unwrap() {
-                    return Err(RustineErrorKind::UnsupportedVersion { version }.into());
-                }
-
-                deserialize_rustine2(data)
-            }
-            PatchFormat::Bsdiff4 => {
-                // Raw BSDIFF4 patch
-                Ok(PatchData::new(data.to_vec()))
-            }
-        }
+        PatchView::parse(data).map(Self::from)
     }
 }
 
-/// Helper to read fixed-size data and advance offset
-fn read_bytes<const N: usize>(
-    data: &[u8],
-    offset: &mut usize,
-    field_name: &str,
-) -> Result<[u8; N]> {
-    if data.len() < *offset + N {
-        return Err(RustineErrorKind::CorruptedPatch {
-            details: format!("truncated {}", field_name),
-        }
-        .into());
-    }
-    let bytes: [u8; N] = data[*offset..*offset + N].try_into().unwrap();
-    *offset += N;
-    Ok(bytes)
+/// Borrowed view of a serialized patch
+///
+/// Same fields as [`PatchData`], but the payloads are slices into the input
+/// buffer, so parsing a memory-mapped patch copies nothing.
+#[derive(Debug, Clone)]
+pub struct PatchView<'a> {
+    /// Container format, payloads of foreign formats are the whole input
+    pub format: PatchFormat,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub base_checksum: Option<[u8; 32]>,
+    pub output_checksum: Option<[u8; 32]>,
+    pub forward_patch: &'a [u8],
+    pub reverse_patch: Option<&'a [u8]>,
+    pub metadata: Option<String>,
+    pub signature: Option<Signature>,
 }
 
-/// Helper to read u32 little-endian
-fn read_u32_le(data: &[u8], offset: &mut usize, field_name: &str) -> Result<u32> {
-    let bytes = read_bytes::<U32_SIZE>(data, offset, field_name)?;
-    Ok(u32::from_le_bytes(bytes))
-}
+impl<'a> PatchView<'a> {
+    /// Parse header and borrow payloads from bytes
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let reader = PatchReader::new(Cursor::new(data))?;
+        let header = reader.header().clone();
+
+        let section = |range: Range<u64>| &data[range.start as usize..range.end as usize];
+
+        Ok(Self {
+            format: reader.format(),
+            hash_algorithm: header.hash_algorithm,
+            compression: header.compression,
+            base_checksum: header.base_checksum,
+            output_checksum: header.output_checksum,
+            forward_patch: section(reader.forward_range()),
+            reverse_patch: reader.reverse_range().map(section),
+            metadata: header.metadata,
+            signature: header.signature,
+        })
+    }
 
-/// Helper to read u64 little-endian
-fn read_u64_le(data: &[u8], offset: &mut usize, field_name: &str) -> Result<u64> {
-    let bytes = read_bytes::<U64_SIZE>(data, offset, field_name)?;
-    Ok(u64::from_le_bytes(bytes))
+    /// Parse metadata (empty when absent)
+    pub fn meta(&self) -> Result<Metadata> {
+        self.metadata
+            .as_deref()
+            .map_or_else(|| Ok(Metadata::default()), Metadata::parse)
+    }
 }
 
-/// Helper to read variable-length data
-fn read_var_bytes(
-    data: &[u8],
-    offset: &mut usize,
-    len: usize,
-    field_name: &str,
-) -> Result<Vec<u8>> {
-    if data.len() < *offset + len {
-        return Err(RustineErrorKind::CorruptedPatch {
-            details: format!("truncated {}", field_name),
+impl From<PatchView<'_>> for PatchData {
+    fn from(view: PatchView<'_>) -> Self {
+        Self {
+            hash_algorithm: view.hash_algorithm,
+            compression: view.compression,
+            base_checksum: view.base_checksum,
+            output_checksum: view.output_checksum,
+            forward_patch: view.forward_patch.to_vec(),
+            reverse_patch: view.reverse_patch.map(<[u8]>::to_vec),
+            metadata: view.metadata,
         }
-        .into());
     }
-    let bytes = data[*offset..*offset + len].to_vec();
-    *offset += len;
-    Ok(bytes)
 }
 
-/// Deserialize RUSTINE2 format
-fn deserialize_rustine2(data: &[u8]) -> Result<PatchData> {
-    let flags = u32::from_le_bytes([data[9], data[10], data[11], data[12]]);
-    let mut offset = RUSTINE2_HEADER_SIZE;
-
-    // Read optional checksums
-    let base_checksum = if flags & FLAG_BASE_CHECKSUM != 0 {
-        Some(read_bytes::<HASH_SIZE>(data, &mut offset, "base checksum")?)
-    } else {
-        None
-    };
-
-    let output_checksum = if flags & FLAG_OUTPUT_CHECKSUM != 0 {
-        Some(read_bytes::<HASH_SIZE>(
-            data,
-            &mut offset,
-            "output checksum",
-        )?)
-    } else {
-        None
-    };
-
-    // Read optional metadata
-    let metadata = if flags & FLAG_METADATA != 0 {
-        let meta_len = read_u32_le(data, &mut offset, "metadata length")? as usize;
-        let meta_bytes = read_var_bytes(data, &mut offset, meta_len, "metadata")?;
-        Some(String::from_utf8_lossy(&meta_bytes).to_string())
-    } else {
-        None
-    };
-
-    // Read forward patch
-    let forward_len = read_u64_le(data, &mut offset, "forward patch length")? as usize;
-    let forward_patch = read_var_bytes(data, &mut offset, forward_len, "forward patch data")?;
-
-    // Read reverse patch if present
-    let reverse_patch = if flags & FLAG_REVERSE_PATCH != 0 {
-        let reverse_len = read_u64_le(data, &mut offset, "reverse patch length")? as usize;
-        Some(read_var_bytes(
-            data,
-            &mut offset,
-            reverse_len,
-            "reverse patch data",
-        )?)
-    } else {
-        None
-    };
-
-    Ok(PatchData {
-        base_checksum,
-        output_checksum,
-        forward_patch,
-        reverse_patch,
-        metadata,
-    })
+/// Compute digest of data with the given algorithm
+pub fn hash(algorithm: HashAlgorithm, data: &[u8]) -> [u8; 32] {
+    algorithm.digest(data)
 }
 
-/// Compute SHA256-like hash of data (using DefaultHasher for simplicity)
-pub fn hash(data: &[u8]) -> [u8; 32] {
+/// Hash used by version 2 patches. Not collision resistant and not stable
+/// across Rust releases, only kept to read old patches.
+fn legacy_hash(data: &[u8]) -> [u8; 32] {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
 
@@ -313,8 +452,8 @@ pub fn hash(data: &[u8]) -> [u8; 32] {
 }
 
 /// Verify hash matches expected
-pub fn verify_hash(data: &[u8], expected: &[u8; 32]) -> Result<()> {
-    let actual = hash(data);
+pub fn verify_hash(algorithm: HashAlgorithm, data: &[u8], expected: &[u8; 32]) -> Result<()> {
+    let actual = hash(algorithm, data);
     if actual != *expected {
         return Err(RustineErrorKind::ChecksumMismatch {
             expected: hex_encode(expected),
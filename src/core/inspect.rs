@@ -1,25 +1,143 @@
+use std::collections::BTreeMap;
+
+use facet::Facet;
+
 use crate::{Result, RustineErrorKind};
 
+use super::control::{Control, ControlStream};
+use super::format::PatchFormat;
+use super::preview::PatchRegion;
+
 /// Information about a patch file
-#[derive(Debug)]
+#[derive(Debug, Facet)]
 pub struct PatchInfo {
     pub patch_size: u64,
     pub expected_output_size: u64,
     pub format_version: String,
     pub is_valid: bool,
     pub has_checksums: bool,
+    pub hash_algorithm: Option<String>,
+    pub codec: String,
     pub has_reverse: bool,
     pub base_checksum: Option<String>,
     pub output_checksum: Option<String>,
+    pub signer: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub archive: Option<super::archive::ArchiveSummary>,
+    /// Record count and embedded CRC32s of IPS, UPS and BPS patches
+    pub rom: Option<super::rom::RomSummary>,
+    /// Control stream statistics, only gathered for deep inspection
+    pub control: Option<ControlStats>,
+    /// Output regions and where they come from, only mapped on request
+    pub regions: Option<Vec<PatchRegion>>,
+}
+
+/// What to decode beyond the header
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InspectOptions {
+    /// Walk every control triple of the forward payload
+    pub deep: bool,
+    /// Map the base regions the forward payload reads and changes
+    pub regions: bool,
+}
+
+/// Statistics over the control triples of a bsdiff payload
+#[derive(Debug, Clone, Default, PartialEq, Eq, Facet)]
+pub struct ControlStats {
+    /// Number of control triples
+    pub entries: u64,
+    /// Output bytes built from the base plus diff bytes
+    pub base_bytes: u64,
+    /// Literal output bytes from the extra block
+    pub extra_bytes: u64,
+    /// Seek with the largest magnitude
+    pub largest_seek: i64,
+    /// Copy lengths by power-of-two bucket, empty buckets left out
+    pub copy_lengths: Vec<Bucket>,
+}
+
+/// Histogram bucket of lengths in `min..=max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Facet)]
+pub struct Bucket {
+    pub min: u64,
+    pub max: u64,
+    pub count: u64,
+}
+
+impl ControlStats {
+    /// Gather statistics over control triples
+    pub fn collect<'a>(controls: impl IntoIterator<Item = &'a Control>) -> Self {
+        let mut stats = Self::default();
+        // Bucket 0 holds empty copies, bucket n lengths in 2^(n-1)..2^n
+        let mut counts = [0u64; 65];
+
+        for control in controls {
+            stats.entries += 1;
+            stats.base_bytes += control.add;
+            stats.extra_bytes += control.copy;
+            if control.seek.unsigned_abs() > stats.largest_seek.unsigned_abs() {
+                stats.largest_seek = control.seek;
+            }
+            counts[(u64::BITS - control.copy.leading_zeros()) as usize] += 1;
+        }
+
+        stats.copy_lengths = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, &count)| match bucket {
+                0 => Bucket {
+                    min: 0,
+                    max: 0,
+                    count,
+                },
+                _ => Bucket {
+                    min: 1 << (bucket - 1),
+                    max: u64::MAX >> (64 - bucket),
+                    count,
+                },
+            })
+            .collect();
+        stats
+    }
+
+    /// Share of the output taken from the base, in percent
+    pub fn base_share(&self) -> f64 {
+        let total = self.base_bytes + self.extra_bytes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.base_bytes as f64 / total as f64 * 100.0
+    }
 }
 
 /// Inspect a patch file and extract metadata
 pub fn inspect(patch_file_data: &[u8]) -> Result<PatchInfo> {
-    // Deserialize using new format
-    let patch = super::format::PatchData::deserialize(patch_file_data)?;
+    inspect_with(patch_file_data, InspectOptions::default())
+}
 
-    // Try to parse the forward patch header to validate
-    let is_valid = qbsdiff::Bspatch::new(&patch.forward_patch).is_ok();
+/// Inspect a patch file, decoding as much as `options` asks for
+pub fn inspect_with(patch_file_data: &[u8], options: InspectOptions) -> Result<PatchInfo> {
+    if PatchFormat::detect(patch_file_data) == PatchFormat::Archive {
+        return inspect_archive(patch_file_data, options);
+    }
+
+    // Borrow sections without copying them
+    let patch = super::format::PatchView::parse(patch_file_data)?;
+
+    // Decode forward payload, then parse its header to validate
+    let forward_patch = patch.compression.decompress(patch.forward_patch)?;
+    let rom = if patch.format.is_rom() {
+        Some(super::rom::summary(&forward_patch)?)
+    } else {
+        None
+    };
+    let is_valid = match patch.format {
+        // Keep the reason, e.g. unsupported secondary compression
+        PatchFormat::Vcdiff => super::vcdiff::windows(&forward_patch).map(|_| true)?,
+        format if format.is_rom() => true,
+        _ => qbsdiff::Bspatch::new(&forward_patch).is_ok(),
+    };
 
     if !is_valid {
         return Err(RustineErrorKind::InvalidPatch {
@@ -31,28 +149,132 @@ pub fn inspect(patch_file_data: &[u8]) -> Result<PatchInfo> {
     // Parse bsdiff4 header manually to extract metadata
     // Header format: "BSDIFF40" (8 bytes) + ctrl_len (8) + diff_len (8) + new_size (8)
     let patch_size = patch_file_data.len() as u64;
-    let expected_output_size = if patch.forward_patch.len() >= 32 {
-        i64::from_le_bytes(patch.forward_patch[24..32].try_into().unwrap()) as u64
-    } else {
-        0
+    let expected_output_size = match patch.format {
+        PatchFormat::Vcdiff => super::vcdiff::target_size(&forward_patch)?,
+        // IPS output grows to fit the base, this is the least it can be
+        PatchFormat::Ips => super::rom::Ips::parse(&forward_patch)?.min_output_size(),
+        PatchFormat::Ups | PatchFormat::Bps => {
+            rom.as_ref().and_then(|rom| rom.target_size).unwrap_or(0)
+        }
+        _ if forward_patch.len() >= 32 => {
+            i64::from_le_bytes(forward_patch[24..32].try_into().unwrap()) as u64
+        }
+        _ => 0,
     };
 
     // Determine format version
-    let format = super::format::PatchFormat::detect(patch_file_data);
-    let format_version = format.name().to_string();
+    let format_version = patch.format.name().to_string();
+
+    // UPS and BPS embed CRC32s of both sides
+    let crc = |crc: Option<u32>| crc.map(|crc| format!("{:08x}", crc));
+    let (base_checksum, output_checksum) = match &rom {
+        Some(rom) => (crc(rom.source_crc), crc(rom.target_crc)),
+        None => (
+            patch
+                .base_checksum
+                .map(|h| super::format::hex_encode_public(&h)),
+            patch
+                .output_checksum
+                .map(|h| super::format::hex_encode_public(&h)),
+        ),
+    };
+    let has_checksums = base_checksum.is_some() || output_checksum.is_some();
+    let hash_algorithm = match rom {
+        Some(_) => "crc32",
+        None => patch.hash_algorithm.name(),
+    };
+
+    if !matches!(patch.format, PatchFormat::Rustine2 | PatchFormat::Bsdiff4)
+        && (options.deep || options.regions)
+    {
+        return Err(RustineErrorKind::FormatMismatch {
+            details: format!(
+                "--deep and --regions decode bsdiff control streams, not {}",
+                patch.format.name()
+            ),
+        }
+        .into());
+    }
+    let stream = if options.deep || options.regions {
+        Some(ControlStream::decode(&forward_patch)?)
+    } else {
+        None
+    };
+    let control = stream
+        .as_ref()
+        .filter(|_| options.deep)
+        .map(|stream| ControlStats::collect(&stream.controls));
+    let regions = match stream {
+        Some(ref stream) if options.regions => Some(super::preview::patch_regions(stream)?),
+        _ => None,
+    };
 
     Ok(PatchInfo {
         patch_size,
         expected_output_size,
         format_version,
         is_valid,
-        has_checksums: patch.base_checksum.is_some() || patch.output_checksum.is_some(),
+        has_checksums,
+        hash_algorithm: has_checksums.then(|| hash_algorithm.to_string()),
+        codec: patch.compression.to_string(),
         has_reverse: patch.reverse_patch.is_some(),
-        base_checksum: patch
-            .base_checksum
-            .map(|h| super::format::hex_encode_public(&h)),
-        output_checksum: patch
-            .output_checksum
-            .map(|h| super::format::hex_encode_public(&h)),
+        base_checksum,
+        output_checksum,
+        signer: patch.signature.map(|sig| sig.fingerprint()),
+        metadata: patch
+            .meta()?
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        archive: None,
+        rom,
+        control,
+        regions,
+    })
+}
+
+/// Inspect a directory archive
+fn inspect_archive(patch_file_data: &[u8], options: InspectOptions) -> Result<PatchInfo> {
+    use super::archive::{ArchiveView, EntryKind};
+
+    if options.regions {
+        return Err(RustineErrorKind::FormatMismatch {
+            details: "region maps cover a single file patch, not a directory archive".to_string(),
+        }
+        .into());
+    }
+
+    let archive = ArchiveView::parse(patch_file_data)?;
+
+    // Every modified entry must carry a valid bsdiff patch
+    let mut controls = Vec::new();
+    for entry in &archive.entries {
+        if entry.kind == EntryKind::Modified {
+            let payload = archive.compression.decompress(entry.payload)?;
+            qbsdiff::Bspatch::new(&payload)
+                .map_err(|source| RustineErrorKind::InvalidPatch { source })?;
+            if options.deep {
+                controls.extend(ControlStream::decode(&payload)?.controls);
+            }
+        }
+    }
+
+    Ok(PatchInfo {
+        patch_size: patch_file_data.len() as u64,
+        expected_output_size: archive.output_size(),
+        format_version: PatchFormat::Archive.name().to_string(),
+        is_valid: true,
+        has_checksums: true,
+        hash_algorithm: Some(archive.hash_algorithm.name().to_string()),
+        codec: archive.compression.to_string(),
+        has_reverse: false,
+        base_checksum: None,
+        output_checksum: None,
+        signer: None,
+        metadata: BTreeMap::new(),
+        archive: Some(archive.summary()),
+        rom: None,
+        control: options.deep.then(|| ControlStats::collect(&controls)),
+        regions: None,
     })
 }
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use crate::{Result, RustineErrorKind};
+
+/// Well-known metadata keys
+pub const SOURCE_VERSION: &str = "source_version";
+pub const TARGET_VERSION: &str = "target_version";
+pub const CREATED_AT: &str = "created_at";
+pub const TOOL_VERSION: &str = "tool_version";
+
+/// Patch metadata, stored as a flat TOML table of strings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// Parse from TOML
+    pub fn parse(toml: &str) -> Result<Self> {
+        let entries = facet_toml::from_str::<BTreeMap<String, String>>(toml).map_err(|e| {
+            RustineErrorKind::InvalidMetadata {
+                details: e.to_string(),
+            }
+        })?;
+        Ok(Self { entries })
+    }
+
+    /// Serialize to TOML
+    pub fn to_toml(&self) -> Result<String> {
+        facet_toml::to_string(&self.entries).map_err(|e| {
+            RustineErrorKind::InvalidMetadata {
+                details: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Parse a `key=value` pair
+    pub fn parse_pair(pair: &str) -> Result<(String, String)> {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(RustineErrorKind::InvalidMetadata {
+                details: format!("expected key=value, got `{}`", pair),
+            }
+            .into()),
+        }
+    }
+
+    /// Set a key, replacing any previous value
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.entries.insert(key.into(), value.into());
+    }
+
+    /// Get a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    /// Iterate over entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Version of the base file
+    pub fn source_version(&self) -> Option<&str> {
+        self.get(SOURCE_VERSION)
+    }
+
+    /// Version of the patched file
+    pub fn target_version(&self) -> Option<&str> {
+        self.get(TARGET_VERSION)
+    }
+
+    /// Creation time in seconds since the Unix epoch
+    pub fn created_at(&self) -> Option<u64> {
+        self.get(CREATED_AT).and_then(|v| v.parse().ok())
+    }
+
+    /// Version of rustine that generated the patch
+    pub fn tool_version(&self) -> Option<&str> {
+        self.get(TOOL_VERSION)
+    }
+
+    /// Fill in creation time and tool version unless already set
+    pub fn stamp(&mut self) {
+        if self.get(CREATED_AT).is_none() {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            self.set(CREATED_AT, now.to_string());
+        }
+        if self.get(TOOL_VERSION).is_none() {
+            self.set(TOOL_VERSION, env!("CARGO_PKG_VERSION"));
+        }
+    }
+
+    /// Ensure a key holds the expected value
+    pub fn expect(&self, key: &str, expected: &str) -> Result<()> {
+        match self.get(key) {
+            Some(actual) if actual == expected => Ok(()),
+            actual => Err(RustineErrorKind::MetadataMismatch {
+                key: key.to_string(),
+                expected: expected.to_string(),
+                actual: actual.unwrap_or("<missing>").to_string(),
+            }
+            .into()),
+        }
+    }
+}
@@ -1,5 +1,19 @@
+pub mod archive;
+pub mod codec;
+pub mod compose;
+pub mod control;
+pub mod convert;
 pub mod diff;
+#[cfg(test)]
+mod fixtures;
 pub mod format;
 pub mod inspect;
+pub mod meta;
 pub mod patch;
 pub mod preview;
+pub mod rom;
+pub mod sign;
+pub mod store;
+pub mod stream;
+pub mod vcdiff;
+pub mod verify;
@@ -1,7 +1,19 @@
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use std::borrow::Cow;
+
+use sha2::Digest;
 
 use crate::{Result, RustineErrorKind};
 
+use super::control::{ControlStream, Segment};
+use super::format::PatchFormat;
+use super::rom::{self, Bps, BpsAction, Ips, IpsRecord, Ups};
+use super::vcdiff::{self, AddressCache, Inst};
+
 /// Apply patch to base, return result
 pub fn apply(base: &[u8], patch_data: &[u8]) -> Result<Vec<u8>> {
     let patcher = qbsdiff::Bspatch::new(patch_data)
@@ -20,7 +32,802 @@ pub fn write_to<W: Write>(base: &[u8], patch_data: &[u8], writer: &mut W) -> Res
     let patcher = qbsdiff::Bspatch::new(patch_data)
         .map_err(|e| RustineErrorKind::InvalidPatch { source: e })?;
 
-    patcher
-        .apply(base, writer)
-        .map_err(|e| RustineErrorKind::PatchFailed { source: e }.into())
+    // Keep errors the writer raised itself, like a partial write
+    patcher.apply(base, writer).map_err(|e| {
+        e.downcast::<RustineErrorKind>()
+            .unwrap_or_else(|source| RustineErrorKind::PatchFailed { source })
+            .into()
+    })
+}
+
+/// Apply a decompressed payload of the given format
+pub fn apply_as(format: PatchFormat, base: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        PatchFormat::Vcdiff => apply_vcdiff(base, payload),
+        PatchFormat::Ips => apply_ips(base, payload),
+        PatchFormat::Ups => apply_ups(base, payload),
+        PatchFormat::Bps => apply_bps(base, payload),
+        PatchFormat::Rustine2 | PatchFormat::Bsdiff4 | PatchFormat::Archive => apply(base, payload),
+    }
+}
+
+/// Stream a decompressed payload of the given format to writer
+///
+/// Only bsdiff payloads stream, the others are built in memory first.
+pub fn write_as<W: Write>(
+    format: PatchFormat,
+    base: &[u8],
+    payload: &[u8],
+    writer: &mut W,
+) -> Result<u64> {
+    match format {
+        PatchFormat::Rustine2 | PatchFormat::Bsdiff4 | PatchFormat::Archive => {
+            write_to(base, payload, writer)
+        }
+        _ => {
+            let out = apply_as(format, base, payload)?;
+            writer.write_all(&out)?;
+            Ok(out.len() as u64)
+        }
+    }
+}
+
+/// Apply a VCDIFF patch to base
+///
+/// Windows may copy from the base or from earlier output, so the whole
+/// output is kept in memory. Adler-32 checksums written by xdelta3 are checked.
+pub fn apply_vcdiff(base: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let table = vcdiff::code_table();
+    let mut out = Vec::new();
+
+    for window in vcdiff::windows(patch)? {
+        let source: Cow<[u8]> = match window.source {
+            Some((kind, len, position)) => {
+                let (from, name) = if kind == vcdiff::VCD_SOURCE {
+                    (base, "base file")
+                } else {
+                    (out.as_slice(), "output")
+                };
+                let segment = usize::try_from(position)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(start, len)| from.get(start..start.checked_add(len)?))
+                    .ok_or_else(|| RustineErrorKind::PatchFailed {
+                        source: std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("patch reads past the end of the {}", name),
+                        ),
+                    })?;
+                Cow::Owned(segment.to_vec())
+            }
+            None => Cow::Borrowed(&[]),
+        };
+        let source_len = source.len() as u64;
+
+        let mut instructions = vcdiff::Cursor::new(window.instructions);
+        let mut data = vcdiff::Cursor::new(window.data);
+        let mut addresses = vcdiff::Cursor::new(window.addresses);
+        let mut cache = AddressCache::new();
+        let mut target = Vec::new();
+
+        while !instructions.is_empty() {
+            let code = instructions.byte()?;
+            for op in table[code as usize] {
+                if op.inst == Inst::Noop {
+                    continue;
+                }
+                let size = match op.size {
+                    0 => instructions.varint()?,
+                    size => size as u64,
+                };
+                if target.len() as u64 + size > window.target_len {
+                    return Err(vcdiff::invalid(
+                        "instruction writes past the end of its window",
+                    ));
+                }
+
+                match op.inst {
+                    Inst::Add => target.extend_from_slice(data.take(size)?),
+                    Inst::Run => {
+                        let byte = data.byte()?;
+                        target.resize(target.len() + size as usize, byte);
+                    }
+                    Inst::Copy => {
+                        let here = source_len + target.len() as u64;
+                        let addr = cache.decode(op.mode, here, &mut addresses)?;
+                        if addr + size <= source_len {
+                            target
+                                .extend_from_slice(&source[addr as usize..(addr + size) as usize]);
+                        } else {
+                            // Reads bytes this copy is still writing, one at a time
+                            for at in addr..addr + size {
+                                let byte = match at.checked_sub(source_len) {
+                                    Some(at) => target[at as usize],
+                                    None => source[at as usize],
+                                };
+                                target.push(byte);
+                            }
+                        }
+                    }
+                    Inst::Noop => unreachable!(),
+                }
+            }
+        }
+
+        if target.len() as u64 != window.target_len {
+            return Err(vcdiff::invalid(
+                "window is shorter than its declared length",
+            ));
+        }
+        if let Some(expected) = window.adler32 {
+            let actual = vcdiff::adler32(&target);
+            if actual != expected {
+                return Err(RustineErrorKind::ChecksumMismatch {
+                    expected: format!("adler32 {:08x}", expected),
+                    actual: format!("adler32 {:08x}", actual),
+                }
+                .into());
+            }
+        }
+        out.extend_from_slice(&target);
+    }
+
+    Ok(out)
+}
+
+/// Apply an IPS patch to base
+///
+/// Records overwrite the base, growing it as needed. The truncation
+/// extension sets the final size.
+pub fn apply_ips(base: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let ips = Ips::parse(patch)?;
+    let mut out = base.to_vec();
+
+    for record in &ips.records {
+        let (start, end) = match *record {
+            IpsRecord::Data { offset, .. } | IpsRecord::Run { offset, .. } => {
+                (offset as usize, record.end() as usize)
+            }
+        };
+        if out.len() < end {
+            out.resize(end, 0);
+        }
+        match *record {
+            IpsRecord::Data { bytes, .. } => out[start..end].copy_from_slice(bytes),
+            IpsRecord::Run { byte, .. } => out[start..end].fill(byte),
+        }
+    }
+
+    if let Some(size) = ips.truncate {
+        out.resize(size as usize, 0);
+    }
+    Ok(out)
+}
+
+/// Apply a UPS patch to base, checking the embedded CRC32s
+///
+/// UPS is symmetric: a base matching the target checksum is patched back
+/// into the source.
+pub fn apply_ups(base: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let ups = Ups::parse(patch)?;
+
+    let base_crc = rom::crc32(base);
+    let (output_size, output_crc) =
+        if base.len() as u64 == ups.source_size && base_crc == ups.source_crc {
+            (ups.target_size, ups.target_crc)
+        } else if base.len() as u64 == ups.target_size && base_crc == ups.target_crc {
+            (ups.source_size, ups.source_crc)
+        } else {
+            rom::check_crc(base, ups.source_crc)?;
+            return Err(size_mismatch("base", ups.source_size, base.len()));
+        };
+
+    let mut out = base.to_vec();
+    out.resize(output_size as usize, 0);
+
+    let mut position: u64 = 0;
+    for &(skip, xor) in &ups.records {
+        position += skip;
+        for &byte in xor {
+            if let Some(out) = out.get_mut(position as usize) {
+                *out ^= byte;
+            }
+            position += 1;
+        }
+        // The terminator leaves its byte unchanged
+        position += 1;
+    }
+
+    rom::check_crc(&out, output_crc)?;
+    Ok(out)
+}
+
+/// Apply a BPS patch to base, checking the embedded CRC32s
+pub fn apply_bps(base: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let bps = Bps::parse(patch)?;
+    rom::check_crc(base, bps.source_crc)?;
+    if base.len() as u64 != bps.source_size {
+        return Err(size_mismatch("base", bps.source_size, base.len()));
+    }
+
+    let past_end = || {
+        crate::RustineError::from(RustineErrorKind::PatchFailed {
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "BPS action reads out of bounds",
+            ),
+        })
+    };
+    let range = |start: u64, len: u64, size: usize| {
+        let end = start.checked_add(len).filter(|end| *end <= size as u64);
+        end.map(|end| start as usize..end as usize)
+            .ok_or_else(past_end)
+    };
+
+    let mut out: Vec<u8> = Vec::new();
+    let (mut source, mut target) = (0u64, 0u64);
+    for action in &bps.actions {
+        match *action {
+            BpsAction::SourceRead { len } => {
+                let range = range(out.len() as u64, len, base.len())?;
+                out.extend_from_slice(&base[range]);
+            }
+            BpsAction::TargetRead { bytes } => out.extend_from_slice(bytes),
+            BpsAction::SourceCopy { len, delta } => {
+                source = source.checked_add_signed(delta).ok_or_else(past_end)?;
+                let range = range(source, len, base.len())?;
+                out.extend_from_slice(&base[range]);
+                source += len;
+            }
+            BpsAction::TargetCopy { len, delta } => {
+                target = target.checked_add_signed(delta).ok_or_else(past_end)?;
+                if target >= out.len() as u64 {
+                    return Err(past_end());
+                }
+                // May read bytes this action is still writing
+                for _ in 0..len {
+                    out.push(out[target as usize]);
+                    target += 1;
+                }
+            }
+        }
+        if out.len() as u64 > bps.target_size {
+            return Err(past_end());
+        }
+    }
+
+    if out.len() as u64 != bps.target_size {
+        return Err(size_mismatch("output", bps.target_size, out.len()));
+    }
+    rom::check_crc(&out, bps.target_crc)?;
+    Ok(out)
+}
+
+fn size_mismatch(what: &str, expected: u64, actual: usize) -> crate::RustineError {
+    RustineErrorKind::PatchFailed {
+        source: std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} is {} bytes, the patch expects {}",
+                what, actual, expected
+            ),
+        ),
+    }
+    .into()
+}
+
+/// Suffix of the journal kept next to a file patched in place
+pub const JOURNAL_SUFFIX: &str = ".rustine-journal";
+/// Journal magic
+const JOURNAL_MAGIC: &[u8; 8] = b"RUSTJNL1";
+/// Offset of the progress counter in the journal
+const JOURNAL_PROGRESS: u64 = 8 + 32 + 8;
+/// Largest piece of output moved in one step
+const IN_PLACE_CHUNK: u64 = 1 << 20;
+/// Most steps replayed after an interruption
+const IN_PLACE_BATCH: usize = 64;
+
+/// Journal path for a file patched in place
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(JOURNAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Apply patch to a file in place, resuming an interrupted run if its journal exists
+///
+/// Writes are ordered so that no base region is overwritten before every
+/// step reading it has run. Regions caught in a dependency cycle are saved
+/// to the journal up front. Returns the new file size.
+pub fn apply_in_place(path: &Path, patch_data: &[u8]) -> Result<u64> {
+    let stream = ControlStream::decode(patch_data)?;
+    let digest: [u8; 32] = sha2::Sha256::digest(patch_data).into();
+    let journal_path = journal_path(path);
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    // Resume from the journal, or plan from scratch and write a new one
+    let (base_size, mut journal) = if journal_path.exists() {
+        Journal::open(&journal_path, &digest)?
+    } else {
+        let base_size = file.metadata()?.len();
+        let plan = InPlacePlan::new(&stream, base_size)?;
+        let journal = Journal::create(&journal_path, &digest, base_size, &plan, &mut file)?;
+        (base_size, journal)
+    };
+    let plan = InPlacePlan::new(&stream, base_size)?;
+
+    // Grow first, so every destination exists
+    if file.metadata()?.len() < stream.new_size {
+        file.set_len(stream.new_size)?;
+    }
+
+    // A batch never writes over a region one of its own steps reads,
+    // so replaying it after an interruption is safe
+    let mut batch: Vec<Range<u64>> = Vec::new();
+    let mut buf = Vec::new();
+    for position in journal.progress as usize..plan.order.len() {
+        let step = &plan.steps[plan.order[position]];
+        let dest = step.dest..step.dest + step.len;
+
+        if batch.len() >= IN_PLACE_BATCH || batch.iter().any(|src| overlaps(src, &dest)) {
+            file.sync_data()?;
+            journal.advance(position as u64)?;
+            batch.clear();
+        }
+
+        buf.resize(step.len as usize, 0);
+        match step.source {
+            StepSource::Base { offset, diff } => {
+                match journal.saved(plan.order[position]) {
+                    Some(saved) => journal.read_saved(saved, &mut buf)?,
+                    None => {
+                        file.seek(SeekFrom::Start(offset))?;
+                        file.read_exact(&mut buf)?;
+                        batch.push(offset..offset + step.len);
+                    }
+                }
+                let diff = &stream.diff[diff as usize..(diff + step.len) as usize];
+                for (byte, delta) in buf.iter_mut().zip(diff) {
+                    *byte = byte.wrapping_add(*delta);
+                }
+            }
+            StepSource::Extra { offset } => {
+                buf.copy_from_slice(&stream.extra[offset as usize..(offset + step.len) as usize]);
+            }
+        }
+
+        file.seek(SeekFrom::Start(step.dest))?;
+        file.write_all(&buf)?;
+    }
+
+    file.sync_data()?;
+    journal.advance(plan.order.len() as u64)?;
+    drop(journal);
+
+    // Shrink last, once nothing reads past the new end
+    file.set_len(stream.new_size)?;
+    file.sync_all()?;
+    std::fs::remove_file(&journal_path)?;
+
+    Ok(stream.new_size)
+}
+
+/// Where a step takes its bytes from
+#[derive(Debug, Clone, Copy)]
+enum StepSource {
+    /// Base bytes at `offset`, plus diff bytes at `diff`
+    Base { offset: u64, diff: u64 },
+    /// Extra bytes at `offset`
+    Extra { offset: u64 },
+}
+
+/// One chunk of output written in place
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    dest: u64,
+    len: u64,
+    source: StepSource,
+}
+
+impl Step {
+    fn base_range(&self) -> Option<Range<u64>> {
+        match self.source {
+            StepSource::Base { offset, .. } => Some(offset..offset + self.len),
+            StepSource::Extra { .. } => None,
+        }
+    }
+}
+
+/// Write order for an in-place application
+struct InPlacePlan {
+    steps: Vec<Step>,
+    /// Step indices in execution order
+    order: Vec<usize>,
+    /// Steps whose base bytes must be saved before any write, sorted
+    saved: Vec<usize>,
+}
+
+impl InPlacePlan {
+    fn new(stream: &ControlStream, base_size: u64) -> Result<Self> {
+        let steps = split_steps(stream, base_size)?;
+
+        // Readers sorted by base offset, to find those a write clobbers
+        let mut readers: Vec<(u64, usize)> = steps
+            .iter()
+            .enumerate()
+            .filter_map(|(i, step)| step.base_range().map(|range| (range.start, i)))
+            .collect();
+        readers.sort_unstable();
+
+        // Edge reader → writer: the reader must run before the writer
+        let mut saved = vec![false; steps.len()];
+        let mut blocks: Vec<Vec<usize>> = vec![Vec::new(); steps.len()];
+        let mut waiting = vec![0usize; steps.len()];
+        for (writer, step) in steps.iter().enumerate() {
+            let dest = step.dest..step.dest + step.len;
+            let first = readers.partition_point(|&(start, _)| start + IN_PLACE_CHUNK <= dest.start);
+            for &(start, reader) in &readers[first..] {
+                if start >= dest.end {
+                    break;
+                }
+                if !overlaps(&steps[reader].base_range().unwrap(), &dest) {
+                    continue;
+                }
+                if reader == writer {
+                    // Overlaps itself: a replayed step would read its own output
+                    saved[reader] = true;
+                } else {
+                    blocks[reader].push(writer);
+                    waiting[writer] += 1;
+                }
+            }
+        }
+
+        // Saved steps read the untouched base up front
+        let mut read = vec![false; steps.len()];
+        for (reader, _) in saved.iter().enumerate().filter(|(_, saved)| **saved) {
+            release(reader, &mut read, &blocks, &mut waiting);
+        }
+
+        // Topological order, saving a blocked reader whenever a cycle stalls it
+        let mut order = Vec::with_capacity(steps.len());
+        let mut done = vec![false; steps.len()];
+        let mut ready: Vec<usize> = (0..steps.len())
+            .rev()
+            .filter(|&i| waiting[i] == 0)
+            .collect();
+        let mut next_victim = 0;
+        while order.len() < steps.len() {
+            match ready.pop() {
+                Some(step) if !done[step] => {
+                    done[step] = true;
+                    order.push(step);
+                    for writer in release(step, &mut read, &blocks, &mut waiting) {
+                        ready.push(writer);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    while done[next_victim] || read[next_victim] || blocks[next_victim].is_empty() {
+                        next_victim += 1;
+                    }
+                    saved[next_victim] = true;
+                    ready.extend(release(next_victim, &mut read, &blocks, &mut waiting));
+                }
+            }
+        }
+
+        Ok(Self {
+            steps,
+            order,
+            saved: (0..saved.len()).filter(|&i| saved[i]).collect(),
+        })
+    }
+}
+
+/// Mark a step's base bytes as read, returning writers that became ready
+fn release(
+    reader: usize,
+    read: &mut [bool],
+    blocks: &[Vec<usize>],
+    waiting: &mut [usize],
+) -> Vec<usize> {
+    if std::mem::replace(&mut read[reader], true) {
+        return Vec::new();
+    }
+    blocks[reader]
+        .iter()
+        .copied()
+        .filter(|&writer| {
+            waiting[writer] -= 1;
+            waiting[writer] == 0
+        })
+        .collect()
+}
+
+/// Split control segments into bounded steps
+fn split_steps(stream: &ControlStream, base_size: u64) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+
+    for segment in stream.segments() {
+        let (dest, len) = (segment.output_offset(), segment.len());
+        if let Segment::Add { base_offset, .. } = segment
+            && (base_offset < 0 || base_offset as u64 + len > base_size)
+        {
+            return Err(RustineErrorKind::PatchFailed {
+                source: std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "patch reads past the end of the base file",
+                ),
+            }
+            .into());
+        }
+
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(IN_PLACE_CHUNK);
+            let source = match segment {
+                Segment::Add {
+                    base_offset,
+                    diff_offset,
+                    ..
+                } => StepSource::Base {
+                    offset: base_offset as u64 + done,
+                    diff: diff_offset + done,
+                },
+                Segment::Copy { extra_offset, .. } => StepSource::Extra {
+                    offset: extra_offset + done,
+                },
+            };
+            steps.push(Step {
+                dest: dest + done,
+                len: chunk,
+                source,
+            });
+            done += chunk;
+        }
+    }
+
+    Ok(steps)
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// On-disk record of an in-place application
+///
+/// Layout: magic, patch digest, base size, progress, saved count, then
+/// (step index, length, bytes) for every saved step.
+struct Journal {
+    file: File,
+    progress: u64,
+    /// Step index → offset of its saved bytes
+    saved: Vec<(usize, u64)>,
+}
+
+impl Journal {
+    /// Save base bytes for the plan, then atomically publish the journal
+    fn create(
+        path: &Path,
+        digest: &[u8; 32],
+        base_size: u64,
+        plan: &InPlacePlan,
+        base: &mut File,
+    ) -> Result<Self> {
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(JOURNAL_MAGIC)?;
+        out.write_all(digest)?;
+        out.write_all(&base_size.to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?;
+        out.write_all(&(plan.saved.len() as u64).to_le_bytes())?;
+
+        let mut saved = Vec::with_capacity(plan.saved.len());
+        let mut offset = JOURNAL_PROGRESS + 16;
+        let mut buf = Vec::new();
+        for &index in &plan.saved {
+            let range = plan.steps[index].base_range().unwrap();
+            buf.resize((range.end - range.start) as usize, 0);
+            base.seek(SeekFrom::Start(range.start))?;
+            base.read_exact(&mut buf)?;
+
+            out.write_all(&(index as u64).to_le_bytes())?;
+            out.write_all(&(buf.len() as u64).to_le_bytes())?;
+            out.write_all(&buf)?;
+            saved.push((index, offset + 16));
+            offset += 16 + buf.len() as u64;
+        }
+
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        sync_parent(path)?;
+
+        Ok(Self {
+            file: OpenOptions::new().read(true).write(true).open(path)?,
+            progress: 0,
+            saved,
+        })
+    }
+
+    /// Open an existing journal, returning the base size it was planned for
+    fn open(path: &Path, digest: &[u8; 32]) -> Result<(u64, Self)> {
+        let mismatch = || {
+            crate::RustineError::from(RustineErrorKind::JournalMismatch {
+                path: path.display().to_string(),
+            })
+        };
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = [0u8; JOURNAL_PROGRESS as usize + 16];
+        file.read_exact(&mut header).map_err(|_| mismatch())?;
+        if &header[..8] != JOURNAL_MAGIC || header[8..40] != digest[..] {
+            return Err(mismatch());
+        }
+
+        let field = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let base_size = field(40);
+        let progress = field(JOURNAL_PROGRESS as usize);
+        let count = field(JOURNAL_PROGRESS as usize + 8);
+
+        let mut saved = Vec::new();
+        let mut offset = JOURNAL_PROGRESS + 16;
+        for _ in 0..count {
+            let mut entry = [0u8; 16];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut entry).map_err(|_| mismatch())?;
+            let index = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            saved.push((index as usize, offset + 16));
+            offset += 16 + len;
+        }
+
+        Ok((
+            base_size,
+            Self {
+                file,
+                progress,
+                saved,
+            },
+        ))
+    }
+
+    /// Offset of a step's saved bytes, if it was saved
+    fn saved(&self, index: usize) -> Option<u64> {
+        self.saved
+            .binary_search_by_key(&index, |&(i, _)| i)
+            .ok()
+            .map(|at| self.saved[at].1)
+    }
+
+    fn read_saved(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Durably record that every step before `progress` is on disk
+    fn advance(&mut self, progress: u64) -> Result<()> {
+        self.file.seek(SeekFrom::Start(JOURNAL_PROGRESS))?;
+        self.file.write_all(&progress.to_le_bytes())?;
+        self.file.sync_data()?;
+        self.progress = progress;
+        Ok(())
+    }
+}
+
+/// Make a rename in the parent directory durable
+fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::diff;
+    use crate::core::fixtures::{edited, rotated, sample};
+
+    /// Fresh scratch file holding `data`
+    fn scratch(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustine-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(journal_path(&path));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn in_place_roundtrip() {
+        let base = sample(1, 8192);
+
+        for (i, target) in [edited(&base), rotated(&base), base[1000..5000].to_vec()]
+            .into_iter()
+            .enumerate()
+        {
+            let path = scratch(&format!("roundtrip-{i}"), &base);
+            let patch = diff::create(&base, &target).unwrap();
+
+            let size = apply_in_place(&path, &patch).unwrap();
+            assert_eq!(size, target.len() as u64);
+            assert_eq!(std::fs::read(&path).unwrap(), target);
+            assert!(!journal_path(&path).exists());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn in_place_resumes_from_journal() {
+        let base = sample(3, 8192);
+        let target = rotated(&base);
+        let path = scratch("resume", &base);
+        let patch = diff::create(&base, &target).unwrap();
+
+        // Journal written, then the first step, before the run was cut short
+        let stream = ControlStream::decode(&patch).unwrap();
+        let digest: [u8; 32] = sha2::Sha256::digest(&patch).into();
+        let plan = InPlacePlan::new(&stream, base.len() as u64).unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        Journal::create(
+            &journal_path(&path),
+            &digest,
+            base.len() as u64,
+            &plan,
+            &mut file,
+        )
+        .unwrap();
+        let step = plan.steps[plan.order[0]];
+        file.seek(SeekFrom::Start(step.dest)).unwrap();
+        file.write_all(&target[step.dest as usize..(step.dest + step.len) as usize])
+            .unwrap();
+        drop(file);
+
+        apply_in_place(&path, &patch).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), target);
+        assert!(!journal_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn in_place_refuses_another_patch_journal() {
+        let base = sample(5, 4096);
+        let path = scratch("mismatch", &base);
+        let first = diff::create(&base, &rotated(&base)).unwrap();
+        let second = diff::create(&base, &base[..2048]).unwrap();
+
+        let stream = ControlStream::decode(&first).unwrap();
+        let digest: [u8; 32] = sha2::Sha256::digest(&first).into();
+        let plan = InPlacePlan::new(&stream, base.len() as u64).unwrap();
+        let mut file = File::open(&path).unwrap();
+        Journal::create(
+            &journal_path(&path),
+            &digest,
+            base.len() as u64,
+            &plan,
+            &mut file,
+        )
+        .unwrap();
+
+        assert!(apply_in_place(&path, &second).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), base);
+        std::fs::remove_file(journal_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
 }
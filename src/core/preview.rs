@@ -1,53 +1,137 @@
+use std::ops::Range;
+
+use facet::Facet;
+
+use crate::{Result, RustineErrorKind};
+
+use super::control::{ControlStream, Segment};
+
 /// Represents a change in the binary data
-#[derive(Debug)]
+#[derive(Debug, Facet)]
 pub struct ByteChange {
+    /// Offset in the old data
     pub offset: usize,
+    /// Offset in the new data
+    pub new_offset: usize,
+    pub kind: ChangeKind,
     pub old_bytes: Vec<u8>,
     pub new_bytes: Vec<u8>,
 }
 
-/// Find regions where bytes differ between old and new data
-pub fn find_changes(old: &[u8], new: &[u8]) -> Vec<ByteChange> {
-    let mut changes = Vec::new();
-    let min_len = old.len().min(new.len());
+/// What happened to a changed region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Facet)]
+#[repr(u8)]
+pub enum ChangeKind {
+    /// Bytes overwritten in place
+    Replaced,
+    /// Bytes only in the new data
+    Inserted,
+    /// Bytes only in the old data
+    Deleted,
+    /// Old bytes reused at another position
+    Moved,
+}
 
-    let mut i = 0;
-    while i < min_len {
-        // Find start of difference
-        if old[i] != new[i] {
-            let start = i;
-            let mut end = i;
-
-            // Find end of continuous difference (with small gap tolerance)
-            while end < min_len
-                && (old[end] != new[end] || (end + 1 < min_len && old[end + 1] != new[end + 1]))
-            {
-                end += 1;
-            }
+impl ChangeKind {
+    /// Lowercase name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Replaced => "replaced",
+            Self::Inserted => "inserted",
+            Self::Deleted => "deleted",
+            Self::Moved => "moved",
+        }
+    }
+}
 
-            changes.push(ByteChange {
-                offset: start,
-                old_bytes: old[start..end].to_vec(),
-                new_bytes: new[start..end].to_vec(),
-            });
+/// How changed bytes are grouped into regions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreviewOptions {
+    /// Unchanged bytes bridged inside one region
+    pub gap_tolerance: usize,
+    /// Shorter regions grow to this size, merging with neighbours they overlap
+    pub min_region: usize,
+    /// Snap region bounds outward to multiples of this, 0 or 1 leaves them as is
+    pub alignment: usize,
+    /// Regions to show; only caps the display, every region is still found
+    pub max_regions: Option<usize>,
+}
 
-            i = end;
-        } else {
-            i += 1;
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        Self {
+            gap_tolerance: 1,
+            min_region: 0,
+            alignment: 1,
+            max_regions: None,
         }
     }
+}
+
+impl PreviewOptions {
+    /// Ranges of `0..len` where `differs` holds, grouped by these options
+    ///
+    /// `origin` is the file offset of position 0, so snapping follows file offsets.
+    fn ranges(
+        &self,
+        origin: usize,
+        len: usize,
+        differs: impl Fn(usize) -> bool,
+    ) -> Vec<Range<usize>> {
+        let alignment = self.alignment.max(1);
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+
+        for range in changed_ranges(len, self.gap_tolerance, differs) {
+            let end = range.end.max(range.start + self.min_region);
+            let start = ((origin + range.start) / alignment * alignment).max(origin) - origin;
+            let end = ((origin + end).div_ceil(alignment) * alignment - origin).min(len);
+
+            // Grown or snapped regions may now overlap the previous one
+            match ranges.last_mut() {
+                Some(last) if start < last.end => last.end = last.end.max(end),
+                _ => ranges.push(start..end),
+            }
+        }
+        ranges
+    }
+}
+
+/// Find regions where bytes differ between old and new data
+pub fn find_changes(old: &[u8], new: &[u8]) -> Vec<ByteChange> {
+    find_changes_with(old, new, &PreviewOptions::default())
+}
+
+/// Find regions where bytes differ between old and new data, grouped by `options`
+pub fn find_changes_with(old: &[u8], new: &[u8], options: &PreviewOptions) -> Vec<ByteChange> {
+    let min_len = old.len().min(new.len());
+
+    let mut changes: Vec<ByteChange> = options
+        .ranges(0, min_len, |i| old[i] != new[i])
+        .into_iter()
+        .map(|range| ByteChange {
+            offset: range.start,
+            new_offset: range.start,
+            kind: ChangeKind::Replaced,
+            old_bytes: old[range.clone()].to_vec(),
+            new_bytes: new[range].to_vec(),
+        })
+        .collect();
 
     // Handle size differences
     if old.len() != new.len() {
         if new.len() > old.len() {
             changes.push(ByteChange {
                 offset: old.len(),
+                new_offset: old.len(),
+                kind: ChangeKind::Inserted,
                 old_bytes: vec![],
                 new_bytes: new[old.len()..].to_vec(),
             });
         } else {
             changes.push(ByteChange {
                 offset: new.len(),
+                new_offset: new.len(),
+                kind: ChangeKind::Deleted,
                 old_bytes: old[new.len()..].to_vec(),
                 new_bytes: vec![],
             });
@@ -57,6 +141,288 @@ pub fn find_changes(old: &[u8], new: &[u8]) -> Vec<ByteChange> {
     changes
 }
 
+/// How a region of the output is produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Facet)]
+#[repr(u8)]
+pub enum RegionKind {
+    /// Base bytes carried over unchanged
+    Copied,
+    /// Base bytes changed by diff bytes
+    Modified,
+    /// Literal bytes with no base counterpart
+    Inserted,
+}
+
+/// Region of the output and the base range it is read from
+#[derive(Debug, Clone, PartialEq, Eq, Facet)]
+pub struct PatchRegion {
+    pub kind: RegionKind,
+    pub output_offset: u64,
+    /// Start in the base, `None` for inserts
+    pub base_offset: Option<u64>,
+    pub len: u64,
+}
+
+/// Map which base regions a patch reads and changes, without the base
+pub fn patch_regions(stream: &ControlStream) -> Result<Vec<PatchRegion>> {
+    let mut regions: Vec<PatchRegion> = Vec::new();
+
+    for segment in stream.segments() {
+        match segment {
+            Segment::Add {
+                output_offset,
+                base_offset,
+                diff_offset,
+                len,
+            } => {
+                if base_offset < 0 {
+                    return Err(RustineErrorKind::InvalidPatch {
+                        source: std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "control stream reads before the start of the base",
+                        ),
+                    }
+                    .into());
+                }
+                let diff = &stream.diff[diff_offset as usize..(diff_offset + len) as usize];
+
+                for (start, end, modified) in diff_runs(diff) {
+                    push_region(
+                        &mut regions,
+                        PatchRegion {
+                            kind: if modified {
+                                RegionKind::Modified
+                            } else {
+                                RegionKind::Copied
+                            },
+                            output_offset: output_offset + start as u64,
+                            base_offset: Some(base_offset as u64 + start as u64),
+                            len: (end - start) as u64,
+                        },
+                    );
+                }
+            }
+            Segment::Copy {
+                output_offset, len, ..
+            } => push_region(
+                &mut regions,
+                PatchRegion {
+                    kind: RegionKind::Inserted,
+                    output_offset,
+                    base_offset: None,
+                    len,
+                },
+            ),
+        }
+    }
+
+    Ok(regions)
+}
+
+/// Find changes along the patch structure instead of by position
+///
+/// Base ranges the patch reads in order are compared byte by byte, ranges read
+/// out of order are moves, extra bytes are insertions and base ranges never
+/// read are deletions. `old` and `new` are the base and output of `stream`.
+pub fn aligned_changes(
+    stream: &ControlStream,
+    old: &[u8],
+    new: &[u8],
+    options: &PreviewOptions,
+) -> Vec<ByteChange> {
+    // Base reads in output order, as (output, base, diff, len)
+    let reads: Vec<(usize, usize, usize, usize)> = stream
+        .segments()
+        .filter_map(|segment| match segment {
+            Segment::Add {
+                output_offset,
+                base_offset,
+                diff_offset,
+                len,
+            } if base_offset >= 0 => Some((
+                output_offset as usize,
+                base_offset as usize,
+                diff_offset as usize,
+                len as usize,
+            )),
+            _ => None,
+        })
+        .filter(|&(output, base, _, len)| base + len <= old.len() && output + len <= new.len())
+        .collect();
+    let in_order = increasing_reads(&reads);
+
+    let mut changes = Vec::new();
+    for (index, &(output, base, diff, len)) in reads.iter().enumerate() {
+        if !in_order[index] {
+            changes.push(ByteChange {
+                offset: base,
+                new_offset: output,
+                kind: ChangeKind::Moved,
+                old_bytes: old[base..base + len].to_vec(),
+                new_bytes: new[output..output + len].to_vec(),
+            });
+            continue;
+        }
+        let diff = &stream.diff[diff..diff + len];
+        for range in options.ranges(base, len, |i| diff[i] != 0) {
+            changes.push(ByteChange {
+                offset: base + range.start,
+                new_offset: output + range.start,
+                kind: ChangeKind::Replaced,
+                old_bytes: old[base + range.start..base + range.end].to_vec(),
+                new_bytes: new[output + range.start..output + range.end].to_vec(),
+            });
+        }
+    }
+
+    // Ends of in-order reads as (output, base), to place insertions and deletions
+    let by_output: Vec<(usize, usize)> = reads
+        .iter()
+        .zip(&in_order)
+        .filter(|(_, in_order)| **in_order)
+        .map(|(&(output, base, _, len), _)| (output + len, base + len))
+        .collect();
+    let mut by_base = by_output.clone();
+    by_base.sort_unstable_by_key(|&(_, base)| base);
+
+    for segment in stream.segments() {
+        if let Segment::Copy {
+            output_offset, len, ..
+        } = segment
+        {
+            let (output, len) = (output_offset as usize, len as usize);
+            let before = by_output.partition_point(|&(end, _)| end <= output);
+            let base = before.checked_sub(1).map_or(0, |i| by_output[i].1);
+            changes.push(ByteChange {
+                offset: base,
+                new_offset: output,
+                kind: ChangeKind::Inserted,
+                old_bytes: vec![],
+                new_bytes: new[output..output + len].to_vec(),
+            });
+        }
+    }
+
+    for (start, end) in unread(&reads, old.len()) {
+        let before = by_base.partition_point(|&(_, end)| end <= start);
+        let output = before.checked_sub(1).map_or(0, |i| by_base[i].0);
+        changes.push(ByteChange {
+            offset: start,
+            new_offset: output,
+            kind: ChangeKind::Deleted,
+            old_bytes: old[start..end].to_vec(),
+            new_bytes: vec![],
+        });
+    }
+
+    changes.sort_by_key(|change| (change.new_offset, change.offset));
+    changes
+}
+
+/// Find aligned changes between two buffers, through a bsdiff of them
+pub fn changes_between(
+    old: &[u8],
+    new: &[u8],
+    options: &PreviewOptions,
+) -> Result<Vec<ByteChange>> {
+    let patch = super::diff::create(old, new)?;
+    let stream = ControlStream::decode(&patch)?;
+    Ok(aligned_changes(&stream, old, new, options))
+}
+
+/// Flag the longest run of reads whose base offsets increase with the output
+fn increasing_reads(reads: &[(usize, usize, usize, usize)]) -> Vec<bool> {
+    // Patience sorting: tails[k] ends the best run of length k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![usize::MAX; reads.len()];
+    for (index, &(_, base, _, _)) in reads.iter().enumerate() {
+        let k = tails.partition_point(|&tail| reads[tail].1 < base);
+        if k > 0 {
+            previous[index] = tails[k - 1];
+        }
+        if k == tails.len() {
+            tails.push(index);
+        } else {
+            tails[k] = index;
+        }
+    }
+
+    let mut in_order = vec![false; reads.len()];
+    let mut next = tails.last().copied();
+    while let Some(index) = next {
+        in_order[index] = true;
+        next = (previous[index] != usize::MAX).then(|| previous[index]);
+    }
+    in_order
+}
+
+/// Base ranges no read covers
+fn unread(reads: &[(usize, usize, usize, usize)], len: usize) -> Vec<(usize, usize)> {
+    let mut covered: Vec<(usize, usize)> = reads
+        .iter()
+        .map(|&(_, base, _, len)| (base, base + len))
+        .collect();
+    covered.sort_unstable();
+
+    let mut gaps = Vec::new();
+    let mut cursor = 0;
+    for (start, end) in covered {
+        if start > cursor {
+            gaps.push((cursor, start));
+        }
+        cursor = cursor.max(end);
+    }
+    if cursor < len {
+        gaps.push((cursor, len));
+    }
+    gaps
+}
+
+/// Ranges of `0..len` where `differs` holds, bridging up to `gap` other positions
+fn changed_ranges(len: usize, gap: usize, differs: impl Fn(usize) -> bool) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for i in (0..len).filter(|&i| differs(i)) {
+        match ranges.last_mut() {
+            Some(last) if i - last.end <= gap => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// Split diff bytes into `(start, end, modified)` runs
+///
+/// Zero diff bytes leave the base as is. Modified runs bridge single
+/// unchanged bytes, like [`find_changes`].
+fn diff_runs(diff: &[u8]) -> Vec<(usize, usize, bool)> {
+    let mut runs = Vec::new();
+    let mut cursor = 0;
+    for range in changed_ranges(diff.len(), 1, |i| diff[i] != 0) {
+        if range.start > cursor {
+            runs.push((cursor, range.start, false));
+        }
+        runs.push((range.start, range.end, true));
+        cursor = range.end;
+    }
+    if cursor < diff.len() {
+        runs.push((cursor, diff.len(), false));
+    }
+    runs
+}
+
+/// Append a region, extending the last one if it continues it
+fn push_region(regions: &mut Vec<PatchRegion>, region: PatchRegion) {
+    if let Some(last) = regions.last_mut()
+        && last.kind == region.kind
+        && last.output_offset + last.len == region.output_offset
+        && last.base_offset.map(|base| base + last.len) == region.base_offset
+    {
+        last.len += region.len;
+        return;
+    }
+    regions.push(region);
+}
+
 /// Format bytes as hex with ASCII preview using pretty-hex
 pub fn format_hex_dump(bytes: &[u8], max_bytes: usize) -> String {
     use pretty_hex::{HexConfig, PrettyHex};
@@ -95,11 +461,45 @@ pub fn preview_summary(changes: &[ByteChange]) -> String {
     let total_new_bytes: usize = changes.iter().map(|c| c.new_bytes.len()).sum();
     let regions = changes.len();
 
+    // Break down structural changes, plain replacements are the default
+    let kinds: Vec<String> = [ChangeKind::Inserted, ChangeKind::Deleted, ChangeKind::Moved]
+        .into_iter()
+        .map(|kind| (kind, changes.iter().filter(|c| c.kind == kind).count()))
+        .filter(|(_, count)| *count > 0)
+        .map(|(kind, count)| format!("{} {}", count, kind.name()))
+        .collect();
+    let kinds = if kinds.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", kinds.join(", "))
+    };
+
     format!(
-        "{} change region{}, {} bytes → {} bytes",
+        "{} change region{}{}, {} bytes → {} bytes",
         regions,
         if regions == 1 { "" } else { "s" },
+        kinds,
         total_old_bytes,
         total_new_bytes
     )
 }
+
+/// Summarise a region map by kind
+pub fn regions_summary(regions: &[PatchRegion]) -> String {
+    let total = |kind: RegionKind| -> u64 {
+        regions
+            .iter()
+            .filter(|region| region.kind == kind)
+            .map(|region| region.len)
+            .sum()
+    };
+
+    format!(
+        "{} region{}, {} bytes copied, {} modified, {} inserted",
+        regions.len(),
+        if regions.len() == 1 { "" } else { "s" },
+        total(RegionKind::Copied),
+        total(RegionKind::Modified),
+        total(RegionKind::Inserted)
+    )
+}
@@ -0,0 +1,410 @@
+use facet::Facet;
+
+use crate::{Result, RustineErrorKind};
+
+/// IPS magic
+pub const IPS_MAGIC: &[u8; 5] = b"PATCH";
+/// IPS end of records, optionally followed by a 24-bit truncation size
+const IPS_EOF: &[u8; 3] = b"EOF";
+/// IPS offsets are 24-bit
+pub const IPS_MAX_OFFSET: u64 = 1 << 24;
+/// Longest IPS record
+pub const IPS_MAX_RECORD: usize = 0xffff;
+
+/// UPS magic
+pub const UPS_MAGIC: &[u8; 4] = b"UPS1";
+/// BPS magic
+pub const BPS_MAGIC: &[u8; 4] = b"BPS1";
+/// Source, target and patch CRC32 closing UPS and BPS patches
+const FOOTER_SIZE: usize = 12;
+
+/// Record count and embedded checksums of an IPS, UPS or BPS patch
+#[derive(Debug, Clone, Default, PartialEq, Eq, Facet)]
+pub struct RomSummary {
+    /// IPS records, UPS XOR runs or BPS actions
+    pub records: u64,
+    /// Base size the patch was made for (UPS, BPS)
+    pub source_size: Option<u64>,
+    /// Output size (UPS, BPS, IPS when truncating)
+    pub target_size: Option<u64>,
+    pub source_crc: Option<u32>,
+    pub target_crc: Option<u32>,
+    pub patch_crc: Option<u32>,
+    /// BPS manifest, usually XML
+    pub metadata: Option<String>,
+}
+
+impl std::fmt::Display for RomSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} records", self.records)?;
+        if let Some(crc) = self.patch_crc {
+            write!(f, ", patch crc32 {:08x}", crc)?;
+        }
+        Ok(())
+    }
+}
+
+/// One IPS record
+#[derive(Debug, Clone, Copy)]
+pub enum IpsRecord<'a> {
+    /// Bytes written at `offset`
+    Data { offset: u64, bytes: &'a [u8] },
+    /// `len` copies of `byte` written at `offset`
+    Run { offset: u64, len: u64, byte: u8 },
+}
+
+impl IpsRecord<'_> {
+    /// Output offset just past the record
+    pub fn end(&self) -> u64 {
+        match *self {
+            Self::Data { offset, bytes } => offset + bytes.len() as u64,
+            Self::Run { offset, len, .. } => offset + len,
+        }
+    }
+}
+
+/// Parsed IPS patch
+#[derive(Debug)]
+pub struct Ips<'a> {
+    pub records: Vec<IpsRecord<'a>>,
+    /// Output size set by the truncation extension
+    pub truncate: Option<u64>,
+}
+
+impl<'a> Ips<'a> {
+    pub fn parse(patch: &'a [u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(patch);
+        if cursor.take(IPS_MAGIC.len())? != IPS_MAGIC {
+            return Err(invalid("missing IPS magic"));
+        }
+
+        let mut records = Vec::new();
+        loop {
+            let offset = cursor.take(3)?;
+            if offset == IPS_EOF {
+                break;
+            }
+            let offset = be24(offset);
+            let size = u16::from_be_bytes(cursor.array()?) as usize;
+            records.push(match size {
+                0 => IpsRecord::Run {
+                    offset,
+                    len: u16::from_be_bytes(cursor.array()?) as u64,
+                    byte: cursor.byte()?,
+                },
+                _ => IpsRecord::Data {
+                    offset,
+                    bytes: cursor.take(size)?,
+                },
+            });
+        }
+
+        let truncate = match cursor.remaining() {
+            0 => None,
+            3 => Some(be24(cursor.take(3)?)),
+            _ => return Err(invalid("trailing bytes after IPS end of file")),
+        };
+        Ok(Self { records, truncate })
+    }
+
+    pub fn summary(&self) -> RomSummary {
+        RomSummary {
+            records: self.records.len() as u64,
+            target_size: self.truncate,
+            ..Default::default()
+        }
+    }
+
+    /// Smallest output the records produce, the base may make it larger
+    pub fn min_output_size(&self) -> u64 {
+        self.truncate
+            .unwrap_or_else(|| self.records.iter().map(IpsRecord::end).max().unwrap_or(0))
+    }
+}
+
+/// Parsed UPS patch
+#[derive(Debug)]
+pub struct Ups<'a> {
+    pub source_size: u64,
+    pub target_size: u64,
+    /// Bytes to skip, then XOR bytes up to (not including) the zero terminator
+    pub records: Vec<(u64, &'a [u8])>,
+    pub source_crc: u32,
+    pub target_crc: u32,
+    pub patch_crc: u32,
+}
+
+impl<'a> Ups<'a> {
+    /// Parse a UPS patch, checking its own CRC32
+    pub fn parse(patch: &'a [u8]) -> Result<Self> {
+        let (body, footer) = split_footer(patch, UPS_MAGIC, "UPS")?;
+        let mut cursor = Cursor::new(body);
+        cursor.take(UPS_MAGIC.len())?;
+        let source_size = cursor.number()?;
+        let target_size = cursor.number()?;
+
+        let mut records = Vec::new();
+        while cursor.remaining() > 0 {
+            let skip = cursor.number()?;
+            let rest = &body[cursor.position..];
+            let len = rest
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or_else(|| invalid("unterminated UPS record"))?;
+            records.push((skip, cursor.take(len)?));
+            cursor.byte()?;
+        }
+
+        Ok(Self {
+            source_size,
+            target_size,
+            records,
+            source_crc: footer[0],
+            target_crc: footer[1],
+            patch_crc: footer[2],
+        })
+    }
+
+    pub fn summary(&self) -> RomSummary {
+        RomSummary {
+            records: self.records.len() as u64,
+            source_size: Some(self.source_size),
+            target_size: Some(self.target_size),
+            source_crc: Some(self.source_crc),
+            target_crc: Some(self.target_crc),
+            patch_crc: Some(self.patch_crc),
+            metadata: None,
+        }
+    }
+}
+
+/// One BPS action
+#[derive(Debug, Clone, Copy)]
+pub enum BpsAction<'a> {
+    /// Base bytes at the current output offset
+    SourceRead { len: u64 },
+    /// Literal bytes
+    TargetRead { bytes: &'a [u8] },
+    /// Base bytes, moving the source cursor by `delta` first
+    SourceCopy { len: u64, delta: i64 },
+    /// Earlier output bytes, moving the target cursor by `delta` first
+    TargetCopy { len: u64, delta: i64 },
+}
+
+/// Parsed BPS patch
+#[derive(Debug)]
+pub struct Bps<'a> {
+    pub source_size: u64,
+    pub target_size: u64,
+    pub metadata: &'a [u8],
+    pub actions: Vec<BpsAction<'a>>,
+    pub source_crc: u32,
+    pub target_crc: u32,
+    pub patch_crc: u32,
+}
+
+impl<'a> Bps<'a> {
+    /// Parse a BPS patch, checking its own CRC32
+    pub fn parse(patch: &'a [u8]) -> Result<Self> {
+        let (body, footer) = split_footer(patch, BPS_MAGIC, "BPS")?;
+        let mut cursor = Cursor::new(body);
+        cursor.take(BPS_MAGIC.len())?;
+        let source_size = cursor.number()?;
+        let target_size = cursor.number()?;
+        let metadata_len = cursor.number()?;
+        let metadata = cursor.take(usize::try_from(metadata_len).unwrap_or(usize::MAX))?;
+
+        let mut actions = Vec::new();
+        while cursor.remaining() > 0 {
+            let data = cursor.number()?;
+            let len = (data >> 2) + 1;
+            actions.push(match data & 3 {
+                0 => BpsAction::SourceRead { len },
+                1 => BpsAction::TargetRead {
+                    bytes: cursor.take(usize::try_from(len).unwrap_or(usize::MAX))?,
+                },
+                2 => BpsAction::SourceCopy {
+                    len,
+                    delta: cursor.offset()?,
+                },
+                _ => BpsAction::TargetCopy {
+                    len,
+                    delta: cursor.offset()?,
+                },
+            });
+        }
+
+        Ok(Self {
+            source_size,
+            target_size,
+            metadata,
+            actions,
+            source_crc: footer[0],
+            target_crc: footer[1],
+            patch_crc: footer[2],
+        })
+    }
+
+    pub fn summary(&self) -> RomSummary {
+        RomSummary {
+            records: self.actions.len() as u64,
+            source_size: Some(self.source_size),
+            target_size: Some(self.target_size),
+            source_crc: Some(self.source_crc),
+            target_crc: Some(self.target_crc),
+            patch_crc: Some(self.patch_crc),
+            metadata: (!self.metadata.is_empty())
+                .then(|| String::from_utf8_lossy(self.metadata).into_owned()),
+        }
+    }
+}
+
+/// Summary of any IPS, UPS or BPS patch
+pub fn summary(patch: &[u8]) -> Result<RomSummary> {
+    if patch.starts_with(IPS_MAGIC) {
+        Ips::parse(patch).map(|ips| ips.summary())
+    } else if patch.starts_with(UPS_MAGIC) {
+        Ups::parse(patch).map(|ups| ups.summary())
+    } else {
+        Bps::parse(patch).map(|bps| bps.summary())
+    }
+}
+
+/// CRC32 as used by UPS and BPS
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Fail with a checksum mismatch unless the CRC32 of `data` is `expected`
+pub fn check_crc(data: &[u8], expected: u32) -> Result<()> {
+    let actual = crc32(data);
+    if actual != expected {
+        return Err(RustineErrorKind::ChecksumMismatch {
+            expected: format!("crc32 {:08x}", expected),
+            actual: format!("crc32 {:08x}", actual),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Append a UPS/BPS number: base-128, little-endian, without redundant encodings
+pub fn write_number(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(0x80 | byte);
+            return;
+        }
+        out.push(byte);
+        value -= 1;
+    }
+}
+
+/// Append a BPS relative offset: magnitude shifted left, sign in bit 0
+pub fn write_offset(out: &mut Vec<u8>, delta: i64) {
+    write_number(out, (delta.unsigned_abs() << 1) | (delta < 0) as u64);
+}
+
+/// Append the source, target and patch CRC32 closing a UPS or BPS patch
+pub fn write_footer(out: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+    out.extend_from_slice(&crc32(source).to_le_bytes());
+    out.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(out);
+    out.extend_from_slice(&patch_crc.to_le_bytes());
+}
+
+/// Split off and check the CRC32 footer
+fn split_footer<'a>(patch: &'a [u8], magic: &[u8], name: &str) -> Result<(&'a [u8], [u32; 3])> {
+    if !patch.starts_with(magic) || patch.len() < magic.len() + FOOTER_SIZE {
+        return Err(invalid(&format!("missing {} magic or footer", name)));
+    }
+    let (body, footer) = patch.split_at(patch.len() - FOOTER_SIZE);
+    let crc = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().unwrap());
+    let footer = [crc(0), crc(4), crc(8)];
+
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != footer[2] {
+        return Err(RustineErrorKind::CorruptedPatch {
+            details: format!(
+                "{} patch crc32 is {:08x}, expected {:08x}",
+                name, actual, footer[2]
+            ),
+        }
+        .into());
+    }
+    Ok((body, footer))
+}
+
+fn be24(bytes: &[u8]) -> u64 {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as u64
+}
+
+/// Reader over a patch body
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(invalid("record extends past end of patch"));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Read a UPS/BPS number
+    fn number(&mut self) -> Result<u64> {
+        let (mut value, mut shift) = (0u64, 1u64);
+        loop {
+            let byte = self.byte()?;
+            value = ((byte & 0x7f) as u64)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or_else(|| invalid("number overflows 64 bits"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_mul(128)
+                .ok_or_else(|| invalid("number overflows 64 bits"))?;
+            value = value
+                .checked_add(shift)
+                .ok_or_else(|| invalid("number overflows 64 bits"))?;
+        }
+    }
+
+    /// Read a BPS relative offset
+    fn offset(&mut self) -> Result<i64> {
+        let data = self.number()?;
+        let magnitude = (data >> 1) as i64;
+        Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+pub(crate) fn invalid(details: &str) -> crate::RustineError {
+    RustineErrorKind::InvalidPatch {
+        source: std::io::Error::new(std::io::ErrorKind::InvalidData, details.to_string()),
+    }
+    .into()
+}
@@ -0,0 +1,159 @@
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use crate::{Result, RustineErrorKind};
+
+/// Domain separation context for Ed25519ph signatures
+const CONTEXT: &[u8] = b"rustine patch";
+/// Public key size
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Signature size
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Signature section of a RUSTINE2 patch
+///
+/// Covers every byte of the patch that precedes it (header, checksums,
+/// metadata and payloads).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub public_key: [u8; PUBLIC_KEY_SIZE],
+    pub signature: [u8; SIGNATURE_SIZE],
+    /// Number of leading patch bytes covered by the signature
+    pub signed_len: u64,
+}
+
+impl Signature {
+    /// Fingerprint of the signer's public key
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    /// Verify against a trusted key, `data` being the whole patch file
+    pub fn verify(&self, data: &[u8], trusted: &VerifyingKey) -> Result<()> {
+        if self.public_key != trusted.to_bytes() {
+            return Err(RustineErrorKind::SignatureInvalid {
+                details: format!(
+                    "signed by {}, expected {}",
+                    self.fingerprint(),
+                    fingerprint(trusted.as_bytes())
+                ),
+            }
+            .into());
+        }
+
+        let signed = data.get(..self.signed_len as usize).ok_or_else(|| {
+            RustineErrorKind::CorruptedPatch {
+                details: "signature covers more than the patch".to_string(),
+            }
+        })?;
+
+        trusted
+            .verify_prehashed_strict(
+                Sha512::new().chain_update(signed),
+                Some(CONTEXT),
+                &ed25519_dalek::Signature::from_bytes(&self.signature),
+            )
+            .map_err(|_| {
+                RustineErrorKind::SignatureInvalid {
+                    details: "signature does not match patch contents".to_string(),
+                }
+                .into()
+            })
+    }
+}
+
+/// Incremental signer fed with every byte written to a patch
+pub struct Signer {
+    key: SigningKey,
+    digest: Sha512,
+}
+
+impl Signer {
+    pub fn new(key: SigningKey) -> Self {
+        Self {
+            key,
+            digest: Sha512::new(),
+        }
+    }
+
+    /// Public key of the signer
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// Feed patch bytes
+    pub fn update(&mut self, data: &[u8]) {
+        self.digest.update(data);
+    }
+
+    /// Sign everything fed so far
+    pub fn finish(self) -> Result<[u8; SIGNATURE_SIZE]> {
+        self.key
+            .sign_prehashed(self.digest, Some(CONTEXT))
+            .map(|sig| sig.to_bytes())
+            .map_err(|e| {
+                RustineErrorKind::InvalidKey {
+                    details: e.to_string(),
+                }
+                .into()
+            })
+    }
+}
+
+/// Generate a new signing key from OS randomness
+pub fn generate_key() -> Result<SigningKey> {
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed).map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Encode key bytes for a key file
+pub fn encode_key(bytes: &[u8; 32]) -> String {
+    format!("{}\n", super::format::hex_encode_public(bytes))
+}
+
+/// Parse a secret key file
+pub fn parse_signing_key(text: &str) -> Result<SigningKey> {
+    decode_key(text).map(|bytes| SigningKey::from_bytes(&bytes))
+}
+
+/// Parse a public key file
+pub fn parse_verifying_key(text: &str) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&decode_key(text)?).map_err(|e| {
+        RustineErrorKind::InvalidKey {
+            details: e.to_string(),
+        }
+        .into()
+    })
+}
+
+/// Short hex fingerprint of a public key
+pub fn fingerprint(public_key: &[u8; PUBLIC_KEY_SIZE]) -> String {
+    let digest = sha2::Sha256::digest(public_key);
+    digest[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn decode_key(text: &str) -> Result<[u8; 32]> {
+    let text = text.trim();
+    let invalid = |details: &str| {
+        RustineErrorKind::InvalidKey {
+            details: details.to_string(),
+        }
+        .into()
+    };
+
+    if text.len() != 64 || !text.is_ascii() {
+        return Err(invalid("expected 64 hex characters"));
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+            .map_err(|_| invalid("key is not valid hex"))?;
+    }
+    Ok(bytes)
+}
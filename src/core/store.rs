@@ -0,0 +1,386 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use crate::Result;
+
+use super::format::{HashAlgorithm, PatchFormat, PatchView, hex_encode_public, verify_hash};
+use super::meta::Metadata;
+use super::stream::PatchReader;
+
+/// Shortest checksum prefix accepted as an upgrade target
+pub const MIN_PREFIX: usize = 8;
+
+/// File state in the store graph: hash algorithm id and checksum
+type Node = (u8, [u8; 32]);
+
+/// Patch indexed by the checksums in its header
+#[derive(Debug, Clone)]
+pub struct StoredPatch {
+    pub path: PathBuf,
+    pub hash_algorithm: HashAlgorithm,
+    pub base_checksum: [u8; 32],
+    pub output_checksum: [u8; 32],
+    /// Compressed payload sizes, the cost of each direction
+    pub forward_size: u64,
+    pub reverse_size: Option<u64>,
+    pub source_version: Option<String>,
+    pub target_version: Option<String>,
+}
+
+/// One patch of an upgrade chain
+#[derive(Debug, Clone, Copy)]
+pub struct Step<'a> {
+    pub patch: &'a StoredPatch,
+    /// Apply the reverse section, going from output back to base
+    pub reverse: bool,
+}
+
+impl Step<'_> {
+    /// Checksum of the file this step applies to
+    pub fn from(&self) -> &[u8; 32] {
+        if self.reverse {
+            &self.patch.output_checksum
+        } else {
+            &self.patch.base_checksum
+        }
+    }
+
+    /// Checksum of the file this step produces
+    pub fn to(&self) -> &[u8; 32] {
+        if self.reverse {
+            &self.patch.base_checksum
+        } else {
+            &self.patch.output_checksum
+        }
+    }
+
+    /// Payload bytes to read
+    pub fn size(&self) -> u64 {
+        if self.reverse {
+            self.patch.reverse_size.unwrap_or_default()
+        } else {
+            self.patch.forward_size
+        }
+    }
+
+    /// Apply this step to `data`, checking both checksums
+    pub fn apply(&self, data: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+        let view = PatchView::parse(patch)?;
+        let algorithm = self.patch.hash_algorithm;
+        verify_hash(algorithm, data, self.from())?;
+
+        let payload = if self.reverse {
+            view.reverse_patch
+                .ok_or(crate::RustineErrorKind::MissingReversePatch)?
+        } else {
+            view.forward_patch
+        };
+        let output = super::patch::apply(data, &view.compression.decompress(payload)?)?;
+        verify_hash(algorithm, &output, self.to())?;
+        Ok(output)
+    }
+}
+
+/// Directory of RUSTINE2 patches linking file versions by checksum
+///
+/// Every patch is an edge from its base to its output checksum, and back
+/// when it carries a reverse section.
+#[derive(Debug, Default)]
+pub struct PatchStore {
+    patches: Vec<StoredPatch>,
+}
+
+impl PatchStore {
+    /// Index a patch from its header alone, `false` if it is not a RUSTINE2
+    /// patch with both checksums
+    pub fn insert<R: Read + Seek>(&mut self, path: PathBuf, patch: R) -> bool {
+        let Ok(reader) = PatchReader::new(patch) else {
+            return false;
+        };
+        let header = reader.header();
+        let (PatchFormat::Rustine2, Some(base_checksum), Some(output_checksum)) = (
+            reader.format(),
+            header.base_checksum,
+            header.output_checksum,
+        ) else {
+            return false;
+        };
+        let (forward, reverse) = (reader.forward_range(), reader.reverse_range());
+        let meta = header
+            .metadata
+            .as_deref()
+            .and_then(|toml| Metadata::parse(toml).ok())
+            .unwrap_or_default();
+
+        self.patches.push(StoredPatch {
+            path,
+            hash_algorithm: header.hash_algorithm,
+            base_checksum,
+            output_checksum,
+            forward_size: forward.end - forward.start,
+            reverse_size: reverse.map(|reverse| reverse.end - reverse.start),
+            source_version: meta.source_version().map(str::to_string),
+            target_version: meta.target_version().map(str::to_string),
+        });
+        true
+    }
+
+    pub fn patches(&self) -> &[StoredPatch] {
+        &self.patches
+    }
+
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    /// Cheapest chain by payload size from `base` to a file matching `to`
+    ///
+    /// `to` is a checksum prefix of at least [`MIN_PREFIX`] hex digits or a
+    /// version from the patches' metadata. An empty chain means `base`
+    /// already matches.
+    pub fn route(&self, base: &[u8], to: &str) -> Option<Vec<Step<'_>>> {
+        let mut edges: HashMap<Node, Vec<Step>> = HashMap::new();
+        let mut versions: HashMap<Node, Vec<&str>> = HashMap::new();
+        for patch in &self.patches {
+            let id = patch.hash_algorithm.id();
+            let (base, output) = ((id, patch.base_checksum), (id, patch.output_checksum));
+            edges.entry(base).or_default().push(Step {
+                patch,
+                reverse: false,
+            });
+            if patch.reverse_size.is_some() {
+                edges.entry(output).or_default().push(Step {
+                    patch,
+                    reverse: true,
+                });
+            }
+            versions
+                .entry(base)
+                .or_default()
+                .extend(patch.source_version.as_deref());
+            versions
+                .entry(output)
+                .or_default()
+                .extend(patch.target_version.as_deref());
+        }
+
+        let to_hex = to.to_ascii_lowercase();
+        let is_target = |node: &Node| {
+            versions.get(node).is_some_and(|v| v.contains(&to))
+                || to_hex.len() >= MIN_PREFIX && hex_encode_public(&node.1).starts_with(&to_hex)
+        };
+
+        // Dijkstra from the base, hashed with every algorithm in the store
+        let mut best: HashMap<Node, (u64, Option<Step>)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut algorithms: Vec<HashAlgorithm> =
+            self.patches.iter().map(|p| p.hash_algorithm).collect();
+        algorithms.sort_by_key(HashAlgorithm::id);
+        algorithms.dedup();
+        for algorithm in algorithms {
+            let start = (algorithm.id(), algorithm.digest(base));
+            best.insert(start, (0, None));
+            queue.push(Reverse((0, start)));
+        }
+
+        while let Some(Reverse((cost, node))) = queue.pop() {
+            if cost > best[&node].0 {
+                continue;
+            }
+            if is_target(&node) {
+                // Walk back to the start
+                let mut chain = Vec::new();
+                let mut at = node;
+                while let Some(step) = best[&at].1 {
+                    chain.push(step);
+                    at = (at.0, *step.from());
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+
+            for step in edges.get(&node).into_iter().flatten() {
+                let next = (node.0, *step.to());
+                let cost = cost + step.size();
+                if best.get(&next).is_none_or(|(known, _)| cost < *known) {
+                    best.insert(next, (cost, Some(*step)));
+                    queue.push(Reverse((cost, next)));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::core::diff;
+    use crate::core::fixtures::{edited, sample};
+    use crate::core::format::PatchData;
+
+    /// Store edge from `base` to `output` with the given payload sizes
+    fn edge(
+        algorithm: HashAlgorithm,
+        base: &[u8],
+        output: &[u8],
+        forward_size: u64,
+        reverse_size: Option<u64>,
+    ) -> StoredPatch {
+        StoredPatch {
+            path: PathBuf::from("edge.patch"),
+            hash_algorithm: algorithm,
+            base_checksum: algorithm.digest(base),
+            output_checksum: algorithm.digest(output),
+            forward_size,
+            reverse_size,
+            source_version: None,
+            target_version: None,
+        }
+    }
+
+    fn hex(algorithm: HashAlgorithm, data: &[u8]) -> String {
+        hex_encode_public(&algorithm.digest(data))
+    }
+
+    /// Checksum each step of a chain leads to, and whether it runs in reverse
+    fn hops(chain: &[Step]) -> Vec<([u8; 32], bool)> {
+        chain
+            .iter()
+            .map(|step| (*step.to(), step.reverse))
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_cheapest_chain() {
+        let sha = HashAlgorithm::Sha256;
+        let (a, b, c) = (sample(1, 64), sample(2, 64), sample(3, 64));
+        let store = PatchStore {
+            patches: vec![
+                edge(sha, &a, &c, 100, None),
+                edge(sha, &a, &b, 10, None),
+                edge(sha, &b, &c, 10, None),
+            ],
+        };
+
+        let chain = store.route(&a, &hex(sha, &c)).unwrap();
+        assert_eq!(
+            hops(&chain),
+            [(sha.digest(&b), false), (sha.digest(&c), false)]
+        );
+
+        let store = PatchStore {
+            patches: vec![
+                edge(sha, &a, &c, 15, None),
+                edge(sha, &a, &b, 10, None),
+                edge(sha, &b, &c, 10, None),
+            ],
+        };
+        let chain = store.route(&a, &hex(sha, &c)).unwrap();
+        assert_eq!(hops(&chain), [(sha.digest(&c), false)]);
+    }
+
+    #[test]
+    fn downgrades_through_reverse_sections() {
+        let sha = HashAlgorithm::Sha256;
+        let (a, b, c) = (sample(1, 64), sample(2, 64), sample(3, 64));
+        let store = PatchStore {
+            patches: vec![edge(sha, &a, &b, 10, Some(10)), edge(sha, &b, &c, 10, None)],
+        };
+
+        let chain = store.route(&b, &hex(sha, &a)).unwrap();
+        assert_eq!(hops(&chain), [(sha.digest(&a), true)]);
+        assert_eq!(chain[0].size(), 10);
+        // No reverse section, no way back
+        assert!(store.route(&c, &hex(sha, &b)).is_none());
+    }
+
+    #[test]
+    fn targets_versions_and_checksum_prefixes() {
+        let sha = HashAlgorithm::Sha256;
+        let (a, b) = (sample(1, 64), sample(2, 64));
+        let mut patch = edge(sha, &a, &b, 10, None);
+        patch.source_version = Some("1.0".to_string());
+        patch.target_version = Some("2.0".to_string());
+        let store = PatchStore {
+            patches: vec![patch],
+        };
+
+        let prefix = hex(sha, &b);
+        assert_eq!(store.route(&a, "2.0").unwrap().len(), 1);
+        assert_eq!(store.route(&a, &prefix[..MIN_PREFIX]).unwrap().len(), 1);
+        assert_eq!(
+            store
+                .route(&a, &prefix[..MIN_PREFIX].to_ascii_uppercase())
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(store.route(&a, &prefix[..MIN_PREFIX - 1]).is_none());
+        assert!(store.route(&a, "3.0").is_none());
+    }
+
+    #[test]
+    fn empty_chain_when_the_base_matches() {
+        let sha = HashAlgorithm::Sha256;
+        let (a, b) = (sample(1, 64), sample(2, 64));
+        let mut patch = edge(sha, &a, &b, 10, None);
+        patch.source_version = Some("1.0".to_string());
+        let store = PatchStore {
+            patches: vec![patch],
+        };
+
+        assert!(store.route(&a, &hex(sha, &a)).unwrap().is_empty());
+        assert!(store.route(&a, "1.0").unwrap().is_empty());
+    }
+
+    #[test]
+    fn routes_within_each_hash_algorithm() {
+        let (sha, blake) = (HashAlgorithm::Sha256, HashAlgorithm::Blake3);
+        let (a, b, c) = (sample(1, 64), sample(2, 64), sample(3, 64));
+        let store = PatchStore {
+            patches: vec![edge(sha, &a, &b, 10, None), edge(blake, &b, &c, 10, None)],
+        };
+
+        // Each store algorithm hashes the base, and patches chain within one
+        assert_eq!(store.route(&a, &hex(sha, &b)).unwrap().len(), 1);
+        assert_eq!(store.route(&b, &hex(blake, &c)).unwrap().len(), 1);
+        assert!(store.route(&a, &hex(blake, &c)).is_none());
+    }
+
+    #[test]
+    fn indexes_and_applies_real_patches() {
+        let sha = HashAlgorithm::Sha256;
+        let a = sample(1, 8192);
+        let b = edited(&a);
+        let mut patch = PatchData::new(diff::create(&a, &b).unwrap());
+        patch.base_checksum = Some(sha.digest(&a));
+        patch.output_checksum = Some(sha.digest(&b));
+        patch.reverse_patch = Some(diff::create(&b, &a).unwrap());
+        let bytes = patch.serialize();
+
+        let mut store = PatchStore::default();
+        assert!(store.insert(PathBuf::from("a-b.patch"), Cursor::new(&bytes)));
+        // Raw bsdiff carries no checksums to index
+        let raw = diff::create(&a, &b).unwrap();
+        assert!(!store.insert(PathBuf::from("raw.patch"), Cursor::new(&raw)));
+        assert_eq!(store.len(), 1);
+
+        let up = store.route(&a, &hex(sha, &b)).unwrap();
+        assert_eq!(up[0].apply(&a, &bytes).unwrap(), b);
+        let down = store.route(&b, &hex(sha, &a)).unwrap();
+        assert!(down[0].reverse);
+        assert_eq!(down[0].apply(&b, &bytes).unwrap(), a);
+        // The base checksum is checked before applying
+        assert!(up[0].apply(&b, &bytes).is_err());
+    }
+}
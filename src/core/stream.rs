@@ -0,0 +1,528 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::{Result, RustineError, RustineErrorKind};
+
+use ed25519_dalek::SigningKey;
+
+use super::codec::{Codec, Compression};
+use super::format::{
+    FLAG_BASE_CHECKSUM, FLAG_COMPRESSION, FLAG_METADATA, FLAG_OUTPUT_CHECKSUM, FLAG_REVERSE_PATCH,
+    FLAG_SIGNATURE, HashAlgorithm, PatchFormat,
+};
+use super::sign::{PUBLIC_KEY_SIZE, SIGNATURE_SIZE, Signature, Signer};
+
+/// Size constants
+const HASH_SIZE: usize = 32;
+const U32_SIZE: usize = 4;
+const CODEC_SIZE: usize = 5; // codec(1) + level(4)
+const U64_SIZE: usize = 8;
+const RUSTINE2_LEGACY_HEADER_SIZE: u64 = 13; // magic(8) + version(1) + flags(4)
+const RUSTINE2_HEADER_SIZE: u64 = 14; // magic(8) + version(1) + flags(4) + hash(1)
+
+/// Everything in a RUSTINE2 patch that precedes the payloads
+#[derive(Debug, Clone, Default)]
+pub struct PatchHeader {
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub base_checksum: Option<[u8; 32]>,
+    pub output_checksum: Option<[u8; 32]>,
+    pub metadata: Option<String>,
+    pub has_reverse: bool,
+    /// Filled in by [`PatchReader`]; writers sign with [`PatchWriter::new_signed`]
+    pub signature: Option<Signature>,
+}
+
+impl PatchHeader {
+    /// Feature flags for this header
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0u32;
+        if self.base_checksum.is_some() {
+            flags |= FLAG_BASE_CHECKSUM;
+        }
+        if self.output_checksum.is_some() {
+            flags |= FLAG_OUTPUT_CHECKSUM;
+        }
+        if self.metadata.is_some() {
+            flags |= FLAG_METADATA;
+        }
+        if self.has_reverse {
+            flags |= FLAG_REVERSE_PATCH;
+        }
+        if !self.compression.is_none() {
+            flags |= FLAG_COMPRESSION;
+        }
+        if self.signature.is_some() {
+            flags |= FLAG_SIGNATURE;
+        }
+        flags
+    }
+
+    /// Serialized size in bytes, excluding the payload sections
+    pub fn serialized_size(&self) -> u64 {
+        let mut size = RUSTINE2_HEADER_SIZE;
+        if !self.compression.is_none() {
+            size += CODEC_SIZE as u64;
+        }
+        if self.base_checksum.is_some() {
+            size += HASH_SIZE as u64;
+        }
+        if self.output_checksum.is_some() {
+            size += HASH_SIZE as u64;
+        }
+        if let Some(meta) = &self.metadata {
+            size += (U32_SIZE + meta.len()) as u64;
+        }
+        size
+    }
+}
+
+/// Which payload section a writer expects next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Forward,
+    Reverse,
+    Done,
+}
+
+/// Streaming RUSTINE2 serializer
+///
+/// Writes the header on creation, then each payload section as it is
+/// handed over, so the full patch never has to exist in memory.
+pub struct PatchWriter<W: Write> {
+    out: Tee<W>,
+    next: Section,
+    /// Whether the header announced a reverse section
+    has_reverse: bool,
+    written: u64,
+}
+
+impl<W: Write> PatchWriter<W> {
+    /// Write the header and prepare for the forward payload
+    pub fn new(inner: W, header: &PatchHeader) -> Result<Self> {
+        Self::start(inner, header, None)
+    }
+
+    /// Same as [`PatchWriter::new`], appending an Ed25519 signature on finish
+    pub fn new_signed(inner: W, header: &PatchHeader, key: SigningKey) -> Result<Self> {
+        Self::start(inner, header, Some(Signer::new(key)))
+    }
+
+    fn start(inner: W, header: &PatchHeader, signer: Option<Signer>) -> Result<Self> {
+        let mut flags = header.flags() & !FLAG_SIGNATURE;
+        if signer.is_some() {
+            flags |= FLAG_SIGNATURE;
+        }
+
+        let mut out = Tee { inner, signer };
+        out.write_all(PatchFormat::Rustine2.magic().unwrap())?;
+        out.write_all(&[PatchFormat::Rustine2.version().unwrap()])?;
+        out.write_all(&flags.to_le_bytes())?;
+        out.write_all(&[header.hash_algorithm.id()])?;
+
+        // Write optional codec
+        if !header.compression.is_none() {
+            out.write_all(&[header.compression.codec.id()])?;
+            out.write_all(&header.compression.level.to_le_bytes())?;
+        }
+
+        // Write optional checksums
+        if let Some(hash) = header.base_checksum {
+            out.write_all(&hash)?;
+        }
+        if let Some(hash) = header.output_checksum {
+            out.write_all(&hash)?;
+        }
+
+        // Write optional metadata
+        if let Some(meta) = &header.metadata {
+            out.write_all(&(meta.len() as u32).to_le_bytes())?;
+            out.write_all(meta.as_bytes())?;
+        }
+
+        Ok(Self {
+            out,
+            next: Section::Forward,
+            has_reverse: header.has_reverse,
+            written: header.serialized_size(),
+        })
+    }
+
+    /// Stream `len` bytes of forward payload from reader
+    pub fn write_forward<R: Read>(&mut self, len: u64, payload: R) -> Result<u64> {
+        self.expect(Section::Forward)?;
+        let n = self.write_section(len, payload, "forward patch")?;
+        self.next = if self.has_reverse {
+            Section::Reverse
+        } else {
+            Section::Done
+        };
+        Ok(n)
+    }
+
+    /// Stream `len` bytes of reverse payload from reader
+    pub fn write_reverse<R: Read>(&mut self, len: u64, payload: R) -> Result<u64> {
+        self.expect(Section::Reverse)?;
+        let n = self.write_section(len, payload, "reverse patch")?;
+        self.next = Section::Done;
+        Ok(n)
+    }
+
+    /// Sign if requested, flush and return total bytes written
+    pub fn finish(self) -> Result<u64> {
+        match self.next {
+            Section::Forward => return Err(misuse("forward patch was never written")),
+            Section::Reverse => return Err(misuse("reverse patch was never written")),
+            Section::Done => {}
+        }
+
+        let Tee { mut inner, signer } = self.out;
+        let mut written = self.written;
+
+        // Write signature section, covering everything before it
+        if let Some(signer) = signer {
+            inner.write_all(&signer.public_key())?;
+            inner.write_all(&signer.finish()?)?;
+            written += (PUBLIC_KEY_SIZE + SIGNATURE_SIZE) as u64;
+        }
+
+        inner.flush()?;
+        Ok(written)
+    }
+
+    fn expect(&self, section: Section) -> Result<()> {
+        if self.next != section {
+            return Err(misuse(&format!(
+                "unexpected {:?} section (expected {:?})",
+                section, self.next
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_section<R: Read>(&mut self, len: u64, payload: R, field_name: &str) -> Result<u64> {
+        self.out.write_all(&len.to_le_bytes())?;
+        let copied = io::copy(&mut payload.take(len), &mut self.out)?;
+        if copied != len {
+            return Err(misuse(&format!(
+                "{} ended after {} of {} bytes",
+                field_name, copied, len
+            )));
+        }
+        self.written += U64_SIZE as u64 + len;
+        Ok(len)
+    }
+}
+
+/// Writer that feeds everything it writes to an optional signer
+struct Tee<W: Write> {
+    inner: W,
+    signer: Option<Signer>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(signer) = &mut self.signer {
+            signer.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Streaming patch deserializer for RUSTINE2 and raw BSDIFF4, VCDIFF, IPS, UPS and BPS
+///
+/// Parses only the header; payloads are exposed as bounded readers over
+/// the underlying stream.
+pub struct PatchReader<R: Read + Seek> {
+    inner: R,
+    format: PatchFormat,
+    header: PatchHeader,
+    size: u64,
+    forward: Range<u64>,
+    reverse: Option<Range<u64>>,
+}
+
+impl<R: Read + Seek> PatchReader<R> {
+    /// Parse header and locate payload sections
+    pub fn new(mut inner: R) -> Result<Self> {
+        let size = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+
+        let mut magic = [0u8; 8];
+        let magic_len = size.min(magic.len() as u64) as usize;
+        inner.read_exact(&mut magic[..magic_len])?;
+        let format = PatchFormat::detect(&magic[..magic_len]);
+
+        match format {
+            PatchFormat::Rustine2 => Self::parse_rustine2(inner, size),
+            PatchFormat::Archive => Err(RustineErrorKind::FormatMismatch {
+                details: "this is a directory archive, not a single-file patch".to_string(),
+            }
+            .into()),
+            PatchFormat::Bsdiff4
+            | PatchFormat::Vcdiff
+            | PatchFormat::Ips
+            | PatchFormat::Ups
+            | PatchFormat::Bps => Ok(Self {
+                inner,
+                format,
+                header: PatchHeader::default(),
+                size,
+                forward: 0..size,
+                reverse: None,
+            }),
+        }
+    }
+
+    /// Deserialize RUSTINE2 format, stream positioned after the magic
+    fn parse_rustine2(mut inner: R, size: u64) -> Result<Self> {
+        if size < RUSTINE2_LEGACY_HEADER_SIZE {
+            return Err(RustineErrorKind::CorruptedPatch {
+                details: format!(
+                    "file too small ({} bytes, expected at least {})",
+                    size, RUSTINE2_LEGACY_HEADER_SIZE
+                ),
+            }
+            .into());
+        }
+
+        let [version] = read_array::<1, _>(&mut inner, "version")?;
+        if version != PatchFormat::Rustine2.version().unwrap()
+            && version != PatchFormat::RUSTINE2_LEGACY_VERSION
+        {
+            return Err(RustineErrorKind::UnsupportedVersion { version }.into());
+        }
+
+        let flags = u32::from_le_bytes(read_array::<U32_SIZE, _>(&mut inner, "flags")?);
+
+        // Version 2 patches predate the hash algorithm byte
+        let hash_algorithm = if version == PatchFormat::RUSTINE2_LEGACY_VERSION {
+            HashAlgorithm::Legacy
+        } else {
+            let [id] = read_array::<1, _>(&mut inner, "hash algorithm")?;
+            HashAlgorithm::from_id(id)?
+        };
+
+        // Read optional codec
+        let compression = if flags & FLAG_COMPRESSION != 0 {
+            let [id] = read_array::<1, _>(&mut inner, "codec")?;
+            let level = i32::from_le_bytes(read_array::<U32_SIZE, _>(&mut inner, "codec level")?);
+            Compression {
+                codec: Codec::from_id(id)?,
+                level,
+            }
+        } else {
+            Compression::default()
+        };
+
+        // Read optional checksums
+        let base_checksum = if flags & FLAG_BASE_CHECKSUM != 0 {
+            Some(read_array::<HASH_SIZE, _>(&mut inner, "base checksum")?)
+        } else {
+            None
+        };
+
+        let output_checksum = if flags & FLAG_OUTPUT_CHECKSUM != 0 {
+            Some(read_array::<HASH_SIZE, _>(&mut inner, "output checksum")?)
+        } else {
+            None
+        };
+
+        // Read optional metadata
+        let metadata = if flags & FLAG_METADATA != 0 {
+            let meta_len =
+                u32::from_le_bytes(read_array::<U32_SIZE, _>(&mut inner, "metadata length")?)
+                    as u64;
+            let start = inner.stream_position()?;
+            check_bounds(start, meta_len, size, "metadata")?;
+
+            let mut meta_bytes = vec![0u8; meta_len as usize];
+            read_exact(&mut inner, &mut meta_bytes, "metadata")?;
+            Some(String::from_utf8_lossy(&meta_bytes).to_string())
+        } else {
+            None
+        };
+
+        // Locate forward patch
+        let forward_len = u64::from_le_bytes(read_array::<U64_SIZE, _>(
+            &mut inner,
+            "forward patch length",
+        )?);
+        let forward_start = inner.stream_position()?;
+        check_bounds(forward_start, forward_len, size, "forward patch data")?;
+        let forward = forward_start..forward_start + forward_len;
+
+        // Locate reverse patch if present
+        let reverse = if flags & FLAG_REVERSE_PATCH != 0 {
+            inner.seek(SeekFrom::Start(forward.end))?;
+            let reverse_len = u64::from_le_bytes(read_array::<U64_SIZE, _>(
+                &mut inner,
+                "reverse patch length",
+            )?);
+            let reverse_start = forward.end + U64_SIZE as u64;
+            check_bounds(reverse_start, reverse_len, size, "reverse patch data")?;
+            Some(reverse_start..reverse_start + reverse_len)
+        } else {
+            None
+        };
+
+        // Read signature section if present
+        let signature = if flags & FLAG_SIGNATURE != 0 {
+            let signed_len = reverse.as_ref().unwrap_or(&forward).end;
+            inner.seek(SeekFrom::Start(signed_len))?;
+            Some(Signature {
+                public_key: read_array::<PUBLIC_KEY_SIZE, _>(&mut inner, "signer public key")?,
+                signature: read_array::<SIGNATURE_SIZE, _>(&mut inner, "signature")?,
+                signed_len,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            inner,
+            format: PatchFormat::Rustine2,
+            header: PatchHeader {
+                hash_algorithm,
+                compression,
+                base_checksum,
+                output_checksum,
+                metadata,
+                has_reverse: reverse.is_some(),
+                signature,
+            },
+            size,
+            forward,
+            reverse,
+        })
+    }
+
+    /// Detected container format
+    pub fn format(&self) -> PatchFormat {
+        self.format
+    }
+
+    /// Parsed header (defaults for raw BSDIFF4)
+    pub fn header(&self) -> &PatchHeader {
+        &self.header
+    }
+
+    /// Total size of the patch stream
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Byte range of the forward payload
+    pub fn forward_range(&self) -> Range<u64> {
+        self.forward.clone()
+    }
+
+    /// Byte range of the reverse payload, if present
+    pub fn reverse_range(&self) -> Option<Range<u64>> {
+        self.reverse.clone()
+    }
+
+    /// Bounded reader over the forward payload
+    pub fn forward(&mut self) -> Result<io::Take<&mut R>> {
+        let range = self.forward_range();
+        self.section(range)
+    }
+
+    /// Bounded reader over the reverse payload, if present
+    pub fn reverse(&mut self) -> Result<Option<io::Take<&mut R>>> {
+        match self.reverse_range() {
+            Some(range) => self.section(range).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Give back the underlying stream
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn section(&mut self, range: Range<u64>) -> Result<io::Take<&mut R>> {
+        self.inner.seek(SeekFrom::Start(range.start))?;
+        Ok((&mut self.inner).take(range.end - range.start))
+    }
+}
+
+/// Helper to read fixed-size data
+pub(crate) fn read_array<const N: usize, R: Read>(
+    reader: &mut R,
+    field_name: &str,
+) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    read_exact(reader, &mut bytes, field_name)?;
+    Ok(bytes)
+}
+
+/// Helper to fill a buffer, reporting truncation as a corrupted patch
+pub(crate) fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8], field_name: &str) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            truncated(field_name)
+        } else {
+            e.into()
+        }
+    })
+}
+
+/// Helper to ensure a section fits in the stream
+pub(crate) fn check_bounds(start: u64, len: u64, size: u64, field_name: &str) -> Result<()> {
+    match start.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(truncated(field_name)),
+    }
+}
+
+fn truncated(field_name: &str) -> RustineError {
+    RustineErrorKind::CorruptedPatch {
+        details: format!("truncated {}", field_name),
+    }
+    .into()
+}
+
+fn misuse(details: &str) -> RustineError {
+    RustineErrorKind::from(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        details.to_string(),
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(has_reverse: bool) -> PatchWriter<Vec<u8>> {
+        let header = PatchHeader {
+            has_reverse,
+            ..Default::default()
+        };
+        let mut writer = PatchWriter::new(Vec::new(), &header).unwrap();
+        writer.write_forward(3, &b"fwd"[..]).unwrap();
+        writer
+    }
+
+    #[test]
+    fn finish_requires_announced_reverse() {
+        assert!(writer(true).finish().is_err());
+
+        let mut complete = writer(true);
+        complete.write_reverse(3, &b"rev"[..]).unwrap();
+        assert!(complete.finish().is_ok());
+    }
+
+    #[test]
+    fn reverse_requires_the_flag() {
+        let mut writer = writer(false);
+        assert!(writer.write_reverse(3, &b"rev"[..]).is_err());
+        assert!(writer.finish().is_ok());
+    }
+}
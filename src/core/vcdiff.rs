@@ -0,0 +1,409 @@
+use crate::{Result, RustineErrorKind};
+
+/// VCDIFF magic, followed by the version byte
+pub const MAGIC: &[u8; 3] = &[0xD6, 0xC3, 0xC4];
+/// Only RFC 3284 version 0 is understood
+pub const VERSION: u8 = 0;
+
+/// Header indicator: secondary compressor id follows
+const VCD_DECOMPRESS: u8 = 0x01;
+/// Header indicator: custom code table follows
+const VCD_CODETABLE: u8 = 0x02;
+/// Header indicator: application header follows (xdelta3)
+const VCD_APPHEADER: u8 = 0x04;
+
+/// Window indicator: copies may read a segment of the source
+pub const VCD_SOURCE: u8 = 0x01;
+/// Window indicator: copies may read a segment of earlier target output
+pub const VCD_TARGET: u8 = 0x02;
+/// Window indicator: Adler-32 of the target window follows (xdelta3)
+const VCD_ADLER32: u8 = 0x04;
+
+/// Entries in the near address cache
+const NEAR: usize = 4;
+/// Blocks of 256 entries in the same address cache
+const SAME: usize = 3;
+
+/// Instruction types of the code table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// One half of a code table entry, size 0 means the size follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub inst: Inst,
+    pub size: u8,
+    pub mode: u8,
+}
+
+/// Default code table of RFC 3284 section 5.6
+pub fn code_table() -> [[Op; 2]; 256] {
+    let op = |inst, size, mode| Op { inst, size, mode };
+    let noop = op(Inst::Noop, 0, 0);
+    let mut table = [[noop; 2]; 256];
+    let mut index = 0;
+    let mut push = |first: Op, second: Op| {
+        table[index] = [first, second];
+        index += 1;
+    };
+
+    push(op(Inst::Run, 0, 0), noop);
+    for size in 0..=17 {
+        push(op(Inst::Add, size, 0), noop);
+    }
+    for mode in 0..=8 {
+        push(op(Inst::Copy, 0, mode), noop);
+        for size in 4..=18 {
+            push(op(Inst::Copy, size, mode), noop);
+        }
+    }
+    for mode in 0..=5 {
+        for add in 1..=4 {
+            for copy in 4..=6 {
+                push(op(Inst::Add, add, 0), op(Inst::Copy, copy, mode));
+            }
+        }
+    }
+    for mode in 6..=8 {
+        for add in 1..=4 {
+            push(op(Inst::Add, add, 0), op(Inst::Copy, 4, mode));
+        }
+    }
+    for mode in 0..=8 {
+        push(op(Inst::Copy, 4, mode), op(Inst::Add, 1, 0));
+    }
+    table
+}
+
+/// Code table index of a lone ADD, and whether its size follows
+pub fn add_code(size: u64) -> (u8, bool) {
+    match size {
+        1..=17 => (1 + size as u8, false),
+        _ => (1, true),
+    }
+}
+
+/// Code table index of a lone COPY, and whether its size follows
+pub fn copy_code(size: u64, mode: u8) -> (u8, bool) {
+    let base = 19 + 16 * mode;
+    match size {
+        4..=18 => (base + size as u8 - 3, false),
+        _ => (base, true),
+    }
+}
+
+/// Code table index of an ADD immediately followed by a COPY, if one exists
+pub fn add_copy_code(add: u64, copy: u64, mode: u8) -> Option<u8> {
+    match (add, copy, mode) {
+        (1..=4, 4..=6, 0..=5) => Some(163 + mode * 12 + (add as u8 - 1) * 3 + (copy as u8 - 4)),
+        (1..=4, 4, 6..=8) => Some(235 + (mode - 6) * 4 + (add as u8 - 1)),
+        _ => None,
+    }
+}
+
+/// Code table index of a COPY immediately followed by an ADD, if one exists
+pub fn copy_add_code(copy: u64, mode: u8, add: u64) -> Option<u8> {
+    (copy == 4 && add == 1 && mode <= 8).then_some(247 + mode)
+}
+
+/// Near and same address caches, reset for every window
+pub struct AddressCache {
+    near: [u64; NEAR],
+    next_slot: usize,
+    same: [u64; SAME * 256],
+}
+
+impl AddressCache {
+    pub fn new() -> Self {
+        Self {
+            near: [0; NEAR],
+            next_slot: 0,
+            same: [0; SAME * 256],
+        }
+    }
+
+    /// Decode the address of a COPY at position `here`
+    pub fn decode(&mut self, mode: u8, here: u64, addresses: &mut Cursor) -> Result<u64> {
+        let addr = match mode as usize {
+            0 => addresses.varint()?,
+            1 => here
+                .checked_sub(addresses.varint()?)
+                .ok_or_else(|| invalid("COPY address before the start of the window"))?,
+            m if m < 2 + NEAR => self.near[m - 2]
+                .checked_add(addresses.varint()?)
+                .ok_or_else(|| invalid("COPY address overflows"))?,
+            m if m < 2 + NEAR + SAME => {
+                let byte = addresses.byte()? as usize;
+                self.same[(m - 2 - NEAR) * 256 + byte]
+            }
+            _ => return Err(invalid("unknown address mode")),
+        };
+        if addr >= here {
+            return Err(invalid("COPY address points past the current position"));
+        }
+        self.update(addr);
+        Ok(addr)
+    }
+
+    /// Pick the cheapest mode for `addr` at position `here`, appending its encoding
+    pub fn encode(&mut self, addr: u64, here: u64, addresses: &mut Vec<u8>) -> u8 {
+        let slot = (addr % (SAME as u64 * 256)) as usize;
+        let mode = if self.same[slot] == addr {
+            addresses.push((slot % 256) as u8);
+            (2 + NEAR + slot / 256) as u8
+        } else {
+            // SELF, HERE, then each near slot
+            let candidates = [(0, addr), (1, here - addr)].into_iter().chain(
+                self.near
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, near)| addr >= **near)
+                    .map(|(i, near)| (2 + i as u8, addr - near)),
+            );
+            let (mode, value) = candidates
+                .min_by_key(|&(_, value)| varint_len(value))
+                .expect("SELF is always a candidate");
+            write_varint(addresses, value);
+            mode
+        };
+        self.update(addr);
+        mode
+    }
+
+    fn update(&mut self, addr: u64) {
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR;
+        self.same[(addr % (SAME as u64 * 256)) as usize] = addr;
+    }
+}
+
+impl Default for AddressCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One parsed window, sections borrowed from the patch
+#[derive(Debug)]
+pub struct Window<'a> {
+    /// `VCD_SOURCE` or `VCD_TARGET` with the segment as (length, position)
+    pub source: Option<(u8, u64, u64)>,
+    pub target_len: u64,
+    pub adler32: Option<u32>,
+    pub data: &'a [u8],
+    pub instructions: &'a [u8],
+    pub addresses: &'a [u8],
+}
+
+/// Parse the file header and every window header of a VCDIFF patch
+pub fn windows(patch: &[u8]) -> Result<Vec<Window<'_>>> {
+    let mut cursor = Cursor::new(patch);
+    if cursor.take(3)? != MAGIC {
+        return Err(invalid("missing VCDIFF magic"));
+    }
+    let version = cursor.byte()?;
+    if version != VERSION {
+        return Err(RustineErrorKind::UnsupportedVersion { version }.into());
+    }
+
+    let indicator = cursor.byte()?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        let id = cursor.byte()?;
+        return Err(invalid(&format!(
+            "secondary compression (id {}) is not supported, re-encode with `xdelta3 -S none`",
+            id
+        )));
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(invalid("custom code tables are not supported"));
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let len = cursor.varint()?;
+        cursor.take(len)?;
+    }
+
+    let mut windows = Vec::new();
+    while !cursor.is_empty() {
+        let indicator = cursor.byte()?;
+        let source = match indicator & (VCD_SOURCE | VCD_TARGET) {
+            0 => None,
+            kind @ (VCD_SOURCE | VCD_TARGET) => Some((kind, cursor.varint()?, cursor.varint()?)),
+            _ => return Err(invalid("window reads both source and target")),
+        };
+
+        let delta_len = cursor.varint()?;
+        let delta_start = cursor.position();
+        let target_len = cursor.varint()?;
+        if cursor.byte()? != 0 {
+            return Err(invalid(
+                "compressed window sections are not supported, re-encode with `xdelta3 -S none`",
+            ));
+        }
+        let data_len = cursor.varint()?;
+        let instructions_len = cursor.varint()?;
+        let addresses_len = cursor.varint()?;
+        let adler32 = if indicator & VCD_ADLER32 != 0 {
+            Some(u32::from_be_bytes(cursor.take(4)?.try_into().unwrap()))
+        } else {
+            None
+        };
+
+        let data = cursor.take(data_len)?;
+        let instructions = cursor.take(instructions_len)?;
+        let addresses = cursor.take(addresses_len)?;
+        if (cursor.position() - delta_start) as u64 != delta_len {
+            return Err(invalid("window length does not match its sections"));
+        }
+
+        windows.push(Window {
+            source,
+            target_len,
+            adler32,
+            data,
+            instructions,
+            addresses,
+        });
+    }
+    Ok(windows)
+}
+
+/// Total size of the output, from the window headers
+pub fn target_size(patch: &[u8]) -> Result<u64> {
+    Ok(windows(patch)?.iter().map(|window| window.target_len).sum())
+}
+
+/// Header of a patch with no code table or application header
+pub fn write_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.push(0);
+}
+
+/// Append one window with uncompressed sections
+pub fn write_window(
+    out: &mut Vec<u8>,
+    source: Option<(u64, u64)>,
+    target_len: u64,
+    data: &[u8],
+    instructions: &[u8],
+    addresses: &[u8],
+) {
+    match source {
+        Some((len, position)) => {
+            out.push(VCD_SOURCE);
+            write_varint(out, len);
+            write_varint(out, position);
+        }
+        None => out.push(0),
+    }
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, target_len);
+    delta.push(0);
+    write_varint(&mut delta, data.len() as u64);
+    write_varint(&mut delta, instructions.len() as u64);
+    write_varint(&mut delta, addresses.len() as u64);
+
+    let delta_len = delta.len() + data.len() + instructions.len() + addresses.len();
+    write_varint(out, delta_len as u64);
+    out.extend_from_slice(&delta);
+    out.extend_from_slice(data);
+    out.extend_from_slice(instructions);
+    out.extend_from_slice(addresses);
+}
+
+/// Adler-32 of a target window, as xdelta3 stores it
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that cannot overflow before reducing
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+/// Append a big-endian base-128 integer
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    let mut bytes = [0u8; 10];
+    let mut start = bytes.len() - 1;
+    bytes[start] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value != 0 {
+        start -= 1;
+        bytes[start] = 0x80 | (value & 0x7f) as u8;
+        value >>= 7;
+    }
+    out.extend_from_slice(&bytes[start..]);
+}
+
+fn varint_len(value: u64) -> u32 {
+    (u64::BITS - value.leading_zeros()).div_ceil(7).max(1)
+}
+
+/// Reader over a section of the patch
+pub struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    pub fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn take(&mut self, len: u64) -> Result<&'a [u8]> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.position.checked_add(len))
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid("section extends past end of patch"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Read a big-endian base-128 integer
+    pub fn varint(&mut self) -> Result<u64> {
+        let mut value: u64 = 0;
+        loop {
+            let byte = self.byte()?;
+            if value >> 57 != 0 {
+                return Err(invalid("integer overflows 64 bits"));
+            }
+            value = (value << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+pub(crate) fn invalid(details: &str) -> crate::RustineError {
+    RustineErrorKind::InvalidPatch {
+        source: std::io::Error::new(std::io::ErrorKind::InvalidData, details.to_string()),
+    }
+    .into()
+}
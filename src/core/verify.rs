@@ -0,0 +1,292 @@
+use std::io::Write;
+
+use facet::Facet;
+
+use crate::{Result, RustineErrorKind};
+
+use super::format::{PatchFormat, PatchView, StreamHasher, hex_encode_public};
+use super::rom;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Facet)]
+#[repr(u8)]
+pub enum Status {
+    Pass,
+    Fail,
+    /// Not applicable, or an earlier check failed
+    Skip,
+}
+
+/// One line of a verification report
+#[derive(Debug, Facet)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub details: String,
+}
+
+/// Result of checking a patch against a base and an optional target
+#[derive(Debug, Facet)]
+pub struct VerifyReport {
+    pub passed: bool,
+    pub base_size: u64,
+    /// Size of the patched output, `None` when it could not be produced
+    pub output_size: Option<u64>,
+    pub checks: Vec<Check>,
+}
+
+impl VerifyReport {
+    /// Number of failed checks
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count()
+    }
+
+    /// Turn a failed report into an error
+    pub fn into_result(self) -> Result<Self> {
+        if self.passed {
+            return Ok(self);
+        }
+        Err(RustineErrorKind::VerificationFailed {
+            failed: self.failures(),
+            total: self.checks.len(),
+        }
+        .into())
+    }
+
+    fn push(&mut self, name: &str, status: Status, details: impl Into<String>) {
+        self.passed &= status != Status::Fail;
+        self.checks.push(Check {
+            name: name.to_string(),
+            status,
+            details: details.into(),
+        });
+    }
+
+    /// Mark the checks that could not run
+    fn skip_rest(&mut self, names: &[&str], reason: &str) {
+        for name in names {
+            self.push(name, Status::Skip, reason);
+        }
+    }
+}
+
+/// Check a patch against its base, and the target it should produce if given
+///
+/// The output is hashed and compared as it is produced, never kept in memory.
+pub fn verify(
+    base: &[u8],
+    patch_file: &[u8],
+    target: Option<&[u8]>,
+    reverse: bool,
+) -> VerifyReport {
+    let mut report = VerifyReport {
+        passed: true,
+        base_size: base.len() as u64,
+        output_size: None,
+        checks: Vec::new(),
+    };
+
+    // Header and payload
+    let parsed = PatchView::parse(patch_file).and_then(|patch| {
+        let payload = if reverse {
+            patch
+                .reverse_patch
+                .ok_or(RustineErrorKind::MissingReversePatch)?
+        } else {
+            patch.forward_patch
+        };
+        let payload = patch.compression.decompress(payload)?;
+        let (output_size, rom) = match patch.format {
+            PatchFormat::Vcdiff => (super::vcdiff::target_size(&payload)?, None),
+            format if format.is_rom() => {
+                let summary = rom::summary(&payload)?;
+                (summary.target_size.unwrap_or(0), Some(summary))
+            }
+            _ => {
+                qbsdiff::Bspatch::new(&payload)
+                    .map_err(|e| RustineErrorKind::InvalidPatch { source: e })?;
+                (declared_size(&payload), None)
+            }
+        };
+        Ok((patch, payload, output_size, rom))
+    });
+    let (patch, payload, output_size, rom) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            report.push("header", Status::Fail, err.to_string());
+            report.skip_rest(
+                &["base checksum", "apply", "output checksum", "target"],
+                "header is invalid",
+            );
+            return report;
+        }
+    };
+    report.push(
+        "header",
+        Status::Pass,
+        format!(
+            "{}, codec {}{}",
+            patch.format.name(),
+            patch.compression,
+            if reverse { ", reverse" } else { "" }
+        ),
+    );
+
+    // Reversing swaps which side each checksum describes
+    let (base_hash, output_hash) = if reverse {
+        (patch.output_checksum, patch.base_checksum)
+    } else {
+        (patch.base_checksum, patch.output_checksum)
+    };
+    let algorithm = patch.hash_algorithm;
+
+    // UPS and BPS carry CRC32s of both sides instead
+    let crcs = rom.and_then(|rom| Some((rom.source_crc?, rom.target_crc?)));
+
+    match (base_hash, crcs) {
+        (_, Some((source, target))) => {
+            let actual = rom::crc32(base);
+            if actual == source {
+                report.push("base checksum", Status::Pass, "crc32");
+            } else if actual == target && patch.format == PatchFormat::Ups {
+                report.push(
+                    "base checksum",
+                    Status::Pass,
+                    "crc32, UPS applied backwards",
+                );
+            } else {
+                report.push(
+                    "base checksum",
+                    Status::Fail,
+                    format!("expected crc32 {:08x}, got {:08x}", source, actual),
+                );
+            }
+        }
+        (Some(expected), None) => {
+            let actual = algorithm.digest(base);
+            if actual == expected {
+                report.push("base checksum", Status::Pass, algorithm.name());
+            } else {
+                report.push("base checksum", Status::Fail, mismatch(&expected, &actual));
+            }
+        }
+        (None, None) => report.push("base checksum", Status::Skip, "patch has no checksums"),
+    }
+
+    // Full application into a sink that hashes and compares
+    let mut sink = VerifySink {
+        hasher: algorithm.hasher(output_size),
+        target,
+        written: 0,
+        first_difference: None,
+    };
+    match super::patch::write_as(patch.format, base, &payload, &mut sink) {
+        Ok(size) => {
+            report.output_size = Some(size);
+            report.push("apply", Status::Pass, format!("{} bytes", size));
+        }
+        Err(err) => {
+            report.push("apply", Status::Fail, err.to_string());
+            report.skip_rest(&["output checksum", "target"], "patch did not apply");
+            return report;
+        }
+    }
+
+    let VerifySink {
+        hasher,
+        written,
+        first_difference,
+        ..
+    } = sink;
+    match output_hash {
+        Some(expected) => {
+            let actual = hasher.finalize();
+            if actual == expected {
+                report.push("output checksum", Status::Pass, algorithm.name());
+            } else {
+                report.push(
+                    "output checksum",
+                    Status::Fail,
+                    mismatch(&expected, &actual),
+                );
+            }
+        }
+        None if crcs.is_some() => report.push(
+            "output checksum",
+            Status::Pass,
+            "crc32, checked while applying",
+        ),
+        None => report.push("output checksum", Status::Skip, "patch has no checksums"),
+    }
+
+    match target {
+        Some(target) => match first_difference {
+            Some(offset) => report.push(
+                "target",
+                Status::Fail,
+                format!("first difference at offset 0x{:08x}", offset),
+            ),
+            None if written != target.len() as u64 => report.push(
+                "target",
+                Status::Fail,
+                format!("output is {} bytes, target is {}", written, target.len()),
+            ),
+            None => report.push("target", Status::Pass, "byte-identical"),
+        },
+        None => report.push("target", Status::Skip, "no target given"),
+    }
+
+    report
+}
+
+/// Hashes the output and compares it with the target as it is written
+struct VerifySink<'a> {
+    hasher: StreamHasher,
+    target: Option<&'a [u8]>,
+    written: u64,
+    first_difference: Option<u64>,
+}
+
+impl Write for VerifySink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+
+        if let Some(target) = self.target
+            && self.first_difference.is_none()
+        {
+            let start = (self.written as usize).min(target.len());
+            let expected = &target[start..(start + buf.len()).min(target.len())];
+            self.first_difference = buf
+                .iter()
+                .zip(expected)
+                .position(|(a, b)| a != b)
+                .map(|i| self.written + i as u64);
+        }
+
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Output size from the bsdiff header, 0 if it is missing
+fn declared_size(payload: &[u8]) -> u64 {
+    payload
+        .get(24..32)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()) & !(1 << 63))
+        .unwrap_or(0)
+}
+
+fn mismatch(expected: &[u8; 32], actual: &[u8; 32]) -> String {
+    format!(
+        "expected {}, got {}",
+        hex_encode_public(expected),
+        hex_encode_public(actual)
+    )
+}
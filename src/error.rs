@@ -17,7 +17,11 @@ pub enum RustineErrorKind {
 
     #[error(transparent)]
     #[diagnostic(code(rustine::io))]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
+
+    #[error("invalid arguments: {details}")]
+    #[diagnostic(code(rustine::invalid_arguments), help("see `rustine help <command>`"))]
+    InvalidArguments { details: String },
 
     #[error("output file already exists: {path}")]
     #[diagnostic(code(rustine::file_exists), help("use --force to overwrite"))]
@@ -26,7 +30,7 @@ pub enum RustineErrorKind {
     #[error("failed to parse patch file")]
     #[diagnostic(
         code(rustine::invalid_patch),
-        help("ensure the patch file is a valid RUSTINE2 or BSDIFF4 patch")
+        help("ensure the patch file is a valid RUSTINE2, BSDIFF4, VCDIFF, IPS, UPS or BPS patch")
     )]
     InvalidPatch {
         #[source]
@@ -86,6 +90,120 @@ pub enum RustineErrorKind {
     )]
     UnsupportedVersion { version: u8 },
 
+    #[error("unknown hash algorithm: {name}")]
+    #[diagnostic(
+        code(rustine::unknown_hash_algorithm),
+        help("supported algorithms: sha256, blake3")
+    )]
+    UnknownHashAlgorithm { name: String },
+
+    #[error("unsupported codec: {name}")]
+    #[diagnostic(
+        code(rustine::unsupported_codec),
+        help("supported codecs: none, lzw, zstd, xz, brotli")
+    )]
+    UnsupportedCodec { name: String },
+
+    #[error("invalid {codec} level: {level}")]
+    #[diagnostic(
+        code(rustine::invalid_level),
+        help("{codec} accepts levels {min} to {max}")
+    )]
+    InvalidLevel {
+        codec: String,
+        level: i32,
+        min: i32,
+        max: i32,
+    },
+
+    #[error("patch is not signed")]
+    #[diagnostic(
+        code(rustine::signature_missing),
+        help("generate the patch with --sign <key> to embed a signature")
+    )]
+    SignatureMissing,
+
+    #[error("patch signature is invalid: {details}")]
+    #[diagnostic(
+        code(rustine::signature_invalid),
+        help("the patch may have been tampered with or signed by another key")
+    )]
+    SignatureInvalid { details: String },
+
+    #[error("invalid key: {details}")]
+    #[diagnostic(
+        code(rustine::invalid_key),
+        help("key files hold 64 hex characters, as written by `rustine keygen`")
+    )]
+    InvalidKey { details: String },
+
+    #[error("invalid metadata: {details}")]
+    #[diagnostic(
+        code(rustine::invalid_metadata),
+        help("metadata is a flat TOML table of strings, e.g. target_version = \"2.1\"")
+    )]
+    InvalidMetadata { details: String },
+
+    #[error("metadata mismatch for {key}: expected {expected}, found {actual}")]
+    #[diagnostic(
+        code(rustine::metadata_mismatch),
+        help("this patch was built for a different release")
+    )]
+    MetadataMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("failed to write {path}")]
+    #[diagnostic(
+        code(rustine::partial_write),
+        help("nothing was replaced; check free disk space and permissions, then retry")
+    )]
+    PartialWrite {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("in-place journal does not match this patch: {path}")]
+    #[diagnostic(
+        code(rustine::journal_mismatch),
+        help("resume with the patch that started the run; the file is only half patched")
+    )]
+    JournalMismatch { path: String },
+
+    #[error("patch and input kinds do not match: {details}")]
+    #[diagnostic(
+        code(rustine::format_mismatch),
+        help("directory archives apply to directories, file patches apply to files")
+    )]
+    FormatMismatch { details: String },
+
+    #[error("no chain of patches in the store leads to {target}")]
+    #[diagnostic(
+        code(rustine::no_upgrade_path),
+        help("the store only links RUSTINE2 patches generated with --checksum")
+    )]
+    NoUpgradePath { target: String },
+
+    #[error("patch would not apply: {source}")]
+    #[diagnostic(
+        code(rustine::would_not_apply),
+        help("the patch was built for another base file or is not meant for this one")
+    )]
+    WouldNotApply {
+        #[source]
+        source: Box<RustineError>,
+    },
+
+    #[error("patch verification failed: {failed} of {total} checks failed")]
+    #[diagnostic(
+        code(rustine::verification_failed),
+        help("see the report above for the failing checks")
+    )]
+    VerificationFailed { failed: usize, total: usize },
+
     #[error("patch file is corrupted or truncated")]
     #[diagnostic(
         code(rustine::corrupted_patch),
@@ -94,6 +212,75 @@ pub enum RustineErrorKind {
     CorruptedPatch { details: String },
 }
 
+impl RustineErrorKind {
+    /// Stable process exit code
+    ///
+    /// | code | meaning |
+    /// |------|---------|
+    /// | 1 | I/O error |
+    /// | 2 | invalid arguments, unknown command or bad config |
+    /// | 3 | input file not found |
+    /// | 4 | input file unreadable |
+    /// | 5 | output file exists |
+    /// | 6 | invalid patch |
+    /// | 7 | corrupted patch |
+    /// | 8 | unsupported patch version |
+    /// | 9 | patch and input kinds do not match |
+    /// | 10 | checksum mismatch |
+    /// | 11 | missing reverse patch |
+    /// | 12 | patch failed to apply |
+    /// | 13 | patch generation failed |
+    /// | 14 | unknown hash algorithm or codec |
+    /// | 15 | patch is not signed |
+    /// | 16 | invalid signature |
+    /// | 17 | invalid key |
+    /// | 18 | invalid metadata |
+    /// | 19 | metadata mismatch |
+    /// | 20 | output could not be written |
+    /// | 21 | in-place journal mismatch |
+    /// | 22 | upgrade: no chain of patches to the target |
+    /// | 30 | dry run: patch would not apply |
+    /// | 31 | verify: one or more checks failed |
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Io(_) => 1,
+            Self::Config { .. }
+            | Self::CommandNotFound { .. }
+            | Self::InvalidArguments { .. }
+            | Self::InvalidLevel { .. } => 2,
+            Self::FileNotFound { .. } => 3,
+            Self::FileUnreadable { .. } => 4,
+            Self::FileExists { .. } => 5,
+            Self::InvalidPatch { .. } => 6,
+            Self::CorruptedPatch { .. } => 7,
+            Self::UnsupportedVersion { .. } => 8,
+            Self::FormatMismatch { .. } => 9,
+            Self::ChecksumMismatch { .. } => 10,
+            Self::MissingReversePatch => 11,
+            Self::PatchFailed { .. } => 12,
+            Self::DiffFailed { .. } => 13,
+            Self::UnknownHashAlgorithm { .. } | Self::UnsupportedCodec { .. } => 14,
+            Self::SignatureMissing => 15,
+            Self::SignatureInvalid { .. } => 16,
+            Self::InvalidKey { .. } => 17,
+            Self::InvalidMetadata { .. } => 18,
+            Self::MetadataMismatch { .. } => 19,
+            Self::PartialWrite { .. } => 20,
+            Self::JournalMismatch { .. } => 21,
+            Self::NoUpgradePath { .. } => 22,
+            Self::WouldNotApply { .. } => 30,
+            Self::VerificationFailed { .. } => 31,
+        }
+    }
+}
+
+impl From<std::io::Error> for RustineErrorKind {
+    /// Unwrap a kind smuggled through a `Write` impl, like a partial write
+    fn from(source: std::io::Error) -> Self {
+        source.downcast::<Self>().unwrap_or_else(Self::Io)
+    }
+}
+
 #[derive(Debug)]
 pub struct RustineError {
     pub kind: RustineErrorKind,
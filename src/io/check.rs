@@ -2,8 +2,14 @@ use std::path::Path;
 
 use crate::{Result, RustineErrorKind};
 
+use super::fs::is_stdio;
+
 /// Validate file exists and is readable
 pub fn exists(path: &Path) -> Result<()> {
+    if is_stdio(path) {
+        return Ok(());
+    }
+
     if !path.exists() {
         return Err(RustineErrorKind::FileNotFound {
             path: path.display().to_string(),
@@ -22,7 +28,7 @@ pub fn exists(path: &Path) -> Result<()> {
 
 /// Check if can overwrite, fail if file exists and !force
 pub fn can_write(path: &Path, force: bool) -> Result<()> {
-    if !force && path.exists() {
+    if !force && !is_stdio(path) && path.exists() {
         return Err(RustineErrorKind::FileExists {
             path: path.display().to_string(),
         }
@@ -30,3 +36,14 @@ pub fn can_write(path: &Path, force: bool) -> Result<()> {
     }
     Ok(())
 }
+
+/// Ensure at most one input is read from stdin
+pub fn single_stdin<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Result<()> {
+    if paths.into_iter().filter(|path| is_stdio(path)).count() > 1 {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "only one input can be read from stdin".to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
@@ -1,21 +1,41 @@
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
 
 use crate::{Result, RustineError, RustineErrorContext, RustineErrorKind, ui::Ctx};
 
 use super::check;
 
-/// Threshold for streaming mode (100MB)
-const STREAMING_THRESHOLD: u64 = 100 * 1024 * 1024;
+/// Path standing for stdin or stdout
+pub const STDIO: &str = "-";
+
+/// Temporary names tried before giving up on a target
+const TMP_ATTEMPTS: u32 = 64;
+
+/// Whether a path stands for stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
 
 /// Extract filename from path for display
 pub fn filename(path: &Path) -> std::borrow::Cow<'_, str> {
+    if is_stdio(path) {
+        return STDIO.into();
+    }
     path.file_name().unwrap_or_default().to_string_lossy()
 }
 
 /// Read file with UI feedback
 pub fn read(path: &Path, ctx: &Ctx) -> Result<Vec<u8>> {
+    if is_stdio(path) {
+        ctx.msg("Reading stdin");
+        let mut buf = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+        return Ok(buf);
+    }
+
     ctx.msg(&format!("Reading {}", filename(path)));
 
     std::fs::read(path).map_err(|e| {
@@ -26,40 +46,359 @@ pub fn read(path: &Path, ctx: &Ctx) -> Result<Vec<u8>> {
     })
 }
 
-/// Read file with streaming for large files
-pub fn read_streaming(path: &Path, ctx: &Ctx) -> Result<Vec<u8>> {
-    let metadata = std::fs::metadata(path)?;
-    let size = metadata.len();
+/// Memory-map file read-only with UI feedback
+pub fn map(path: &Path, ctx: &Ctx) -> Result<Mmap> {
+    ctx.msg(&format!("Mapping {}", filename(path)));
 
-    if size > STREAMING_THRESHOLD {
-        ctx.msg(&format!("Reading {} (streaming mode)", filename(path)));
+    let with_path = |e: std::io::Error| {
+        RustineError::new(
+            RustineErrorKind::from(e),
+            RustineErrorContext::default().with_path(path.to_path_buf()),
+        )
+    };
 
-        let file = File::open(path).map_err(|e| {
-            RustineError::new(
-                RustineErrorKind::from(e),
-                RustineErrorContext::default().with_path(path.to_path_buf()),
-            )
-        })?;
+    let file = File::open(path).map_err(with_path)?;
+    // SAFETY: the mapping is read-only. Truncating the file while it is
+    // mapped is undefined behaviour, same as any other mmap-based tool.
+    unsafe { Mmap::map(&file) }.map_err(with_path)
+}
 
-        let mut reader = BufReader::new(file);
-        let mut buffer = Vec::with_capacity(size as usize);
-        reader.read_to_end(&mut buffer).map_err(|e| {
-            RustineError::new(
-                RustineErrorKind::from(e),
-                RustineErrorContext::default().with_path(path.to_path_buf()),
-            )
-        })?;
+/// File contents, memory-mapped when the source allows it
+pub enum Input {
+    /// Read-only mapping of a regular file
+    Mapped(Mmap),
+    /// Heap copy, for pipes, special files and empty files
+    Buffered(Vec<u8>),
+}
+
+impl Input {
+    /// How the contents were loaded, for display
+    pub fn mode(&self) -> &'static str {
+        match self {
+            Self::Mapped(_) => "mmap",
+            Self::Buffered(_) => "buffered",
+        }
+    }
+}
+
+impl std::ops::Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(map) => map,
+            Self::Buffered(buf) => buf,
+        }
+    }
+}
 
-        Ok(buffer)
-    } else {
-        read(path, ctx)
+/// Load file without a heap copy when possible
+///
+/// Regular files are memory-mapped. Pipes, character devices and anything
+/// that refuses to map are read into memory instead.
+pub fn load(path: &Path, ctx: &Ctx) -> Result<Input> {
+    if is_stdio(path) {
+        return read(path, ctx).map(Input::Buffered);
     }
+
+    let metadata = std::fs::metadata(path).map_err(|e| {
+        RustineError::new(
+            RustineErrorKind::from(e),
+            RustineErrorContext::default().with_path(path.to_path_buf()),
+        )
+    })?;
+
+    if metadata.is_file() && metadata.len() > 0 {
+        match map(path, ctx) {
+            Ok(map) => return Ok(Input::Mapped(map)),
+            Err(_) => ctx.msg(&format!("Cannot map {}, reading instead", filename(path))),
+        }
+    }
+
+    read(path, ctx).map(Input::Buffered)
 }
 
-/// Write file with UI feedback and overwrite check
+/// Write file atomically with UI feedback and overwrite check
 pub fn write(path: &Path, data: &[u8], force: bool, ctx: &Ctx) -> Result<u64> {
+    let mut file = create(path, force, ctx)?;
+    file.write_all(data)?;
+    file.commit()?;
+    Ok(data.len() as u64)
+}
+
+/// Open file for buffered streaming reads
+pub fn open(path: &Path, ctx: &Ctx) -> Result<BufReader<File>> {
+    ctx.msg(&format!("Opening {}", filename(path)));
+
+    let file = File::open(path).map_err(|e| {
+        RustineError::new(
+            RustineErrorKind::from(e),
+            RustineErrorContext::default().with_path(path.to_path_buf()),
+        )
+    })?;
+
+    Ok(BufReader::new(file))
+}
+
+/// Create file for buffered streaming writes, with overwrite check
+///
+/// Nothing reaches `path` until [`Output::commit`] is called. `-` writes
+/// to stdout.
+pub fn create(path: &Path, force: bool, ctx: &Ctx) -> Result<Output> {
+    if is_stdio(path) {
+        ctx.msg("Writing stdout");
+        return Ok(Output::Stdout(BufWriter::new(std::io::stdout())));
+    }
+
     check::can_write(path, force)?;
     ctx.msg(&format!("Writing {}", filename(path)));
-    std::fs::write(path, data)?;
+    AtomicFile::new(path, false).map(Output::File)
+}
+
+/// Destination opened by [`create`]
+pub enum Output {
+    File(AtomicFile),
+    Stdout(BufWriter<std::io::Stdout>),
+}
+
+impl Output {
+    /// Publish the file, or flush stdout
+    pub fn commit(self) -> Result<()> {
+        match self {
+            Self::File(file) => file.commit(),
+            Self::Stdout(mut out) => out.flush().map_err(Into::into),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Stdout(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Stdout(out) => out.flush(),
+        }
+    }
+}
+
+/// Write file readable only by its owner (where supported)
+pub fn write_secret(path: &Path, data: &[u8], force: bool, ctx: &Ctx) -> Result<u64> {
+    check::can_write(path, force)?;
+    ctx.msg(&format!("Writing {}", filename(path)));
+
+    let mut file = AtomicFile::new(path, true)?;
+    file.write_all(data)?;
+    file.commit()?;
     Ok(data.len() as u64)
 }
+
+/// Output written to a temporary sibling, then renamed over the target
+///
+/// The target is either left untouched or fully replaced. The temporary
+/// file is removed if the writer is dropped without committing.
+pub struct AtomicFile {
+    inner: BufWriter<File>,
+    path: PathBuf,
+    tmp: PathBuf,
+    secret: bool,
+    committed: bool,
+}
+
+impl AtomicFile {
+    fn new(path: &Path, secret: bool) -> Result<Self> {
+        let dir = parent_dir(path);
+
+        let mut options = std::fs::OpenOptions::new();
+        // Never take over a temporary file another writer still holds
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if secret {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = secret;
+
+        // PIDs repeat across reboots, so a leftover from a crash only costs
+        // one attempt instead of blocking the target
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let mut attempt = 0;
+        let (file, tmp) = loop {
+            let tmp = dir.join(format!(
+                ".{}.{}.{:x}.tmp",
+                filename(path),
+                std::process::id(),
+                seed.wrapping_add(attempt)
+            ));
+            match options.open(&tmp) {
+                Ok(file) => break (file, tmp),
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::AlreadyExists
+                        && attempt + 1 < TMP_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(RustineError::new(
+                        RustineErrorKind::from(e),
+                        RustineErrorContext::default().with_path(path.to_path_buf()),
+                    ));
+                }
+            }
+        };
+
+        Ok(Self {
+            inner: BufWriter::new(file),
+            path: path.to_path_buf(),
+            tmp,
+            secret,
+            committed: false,
+        })
+    }
+
+    /// Flush, fsync and rename over the target, then fsync its directory
+    ///
+    /// A replaced target keeps its permissions, secret files stay owner-only.
+    pub fn commit(mut self) -> Result<()> {
+        let path = self.path.clone();
+        if !self.secret
+            && let Ok(target) = std::fs::metadata(&self.path)
+        {
+            self.inner
+                .get_ref()
+                .set_permissions(target.permissions())
+                .map_err(|e| partial_write(&path, e))?;
+        }
+        self.inner
+            .flush()
+            .and_then(|_| self.inner.get_ref().sync_all())
+            .and_then(|_| std::fs::rename(&self.tmp, &self.path))
+            .map_err(|e| partial_write(&path, e))?;
+        self.committed = true;
+
+        // Make the rename itself durable
+        #[cfg(unix)]
+        File::open(parent_dir(&self.path))
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| partial_write(&path, e))?;
+
+        Ok(())
+    }
+}
+
+/// Failed writes surface as [`RustineErrorKind::PartialWrite`] once converted
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner
+            .write(buf)
+            .map_err(|e| stream_error(&self.path, e))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().map_err(|e| stream_error(&self.path, e))
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Directory holding a path, `.` for bare filenames
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn partial_write(path: &Path, source: std::io::Error) -> RustineError {
+    partial_write_kind(path, source).into()
+}
+
+/// Partial write carried inside an `io::Error`, for `Write` callers
+fn stream_error(path: &Path, source: std::io::Error) -> std::io::Error {
+    std::io::Error::new(source.kind(), partial_write_kind(path, source))
+}
+
+fn partial_write_kind(path: &Path, source: std::io::Error) -> RustineErrorKind {
+    RustineErrorKind::PartialWrite {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// List regular files under a directory as sorted, `/`-separated relative paths
+///
+/// Symlinks are skipped, so links out of the tree or into a cycle are never followed.
+pub fn walk(root: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir).map_err(|e| {
+            RustineError::new(
+                RustineErrorKind::from(e),
+                RustineErrorContext::default().with_path(dir.clone()),
+            )
+        })?;
+
+        for entry in entries {
+            let entry = entry?;
+            // Unlike `Path::is_dir`, the entry's own type does not follow links
+            let kind = entry.file_type()?;
+            let path = entry.path();
+            if kind.is_dir() {
+                pending.push(path);
+            } else if kind.is_file() {
+                let rel = path.strip_prefix(root).unwrap_or(&path);
+                let parts: Vec<_> = rel.iter().map(|part| part.to_string_lossy()).collect();
+                files.push(parts.join("/"));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_write_errors_are_partial_writes() {
+        let error = stream_error(Path::new("out.bin"), std::io::Error::other("disk full"));
+        let error = RustineError::from(error);
+        assert!(matches!(error.kind, RustineErrorKind::PartialWrite { .. }));
+        assert_eq!(error.kind.exit_code(), 20);
+    }
+
+    #[test]
+    fn concurrent_writers_get_their_own_temporary() {
+        let dir = std::env::temp_dir().join(format!("rustine-{}-atomic", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.bin");
+
+        let mut first = AtomicFile::new(&path, false).unwrap();
+        let second = AtomicFile::new(&path, false).unwrap();
+        assert_ne!(first.tmp, second.tmp);
+        first.write_all(b"first").unwrap();
+        first.commit().unwrap();
+        drop(second);
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
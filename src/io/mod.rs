@@ -2,4 +2,7 @@ pub mod check;
 pub mod fs;
 
 pub use check::{can_write, exists};
-pub use fs::{filename, read, read_streaming, write};
+pub use fs::{
+    AtomicFile, Input, Output, STDIO, create, filename, is_stdio, load, map, open, read, walk,
+    write, write_secret,
+};
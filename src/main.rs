@@ -1,16 +1,35 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use facet::Facet;
 use rustine::{
-    Result, RustineErrorKind,
+    Result, RustineError, RustineErrorContext, RustineErrorKind,
     cli::{Command, Opts},
+    config::Config,
     core, io,
-    ui::{self, Ctx, Level},
+    ui::{self, Ctx, Level, OutputFormat},
 };
 
 /// Maximum number of changes to show in verbose preview
 const MAX_PREVIEW_CHANGES: usize = 5;
 
+/// Default number of changes shown by `diff`
+const MAX_DIFF_CHANGES: usize = 50;
+
+/// Default bytes of context around each `diff` change
+const DIFF_CONTEXT: usize = 16;
+
+/// Maximum hex rows shown per `diff` change
+const MAX_DIFF_ROWS: usize = 16;
+
+/// Maximum number of regions to show in a region map
+const MAX_REGIONS: usize = 20;
+
+/// Width of the longest histogram bar
+const HISTOGRAM_WIDTH: u64 = 30;
+
 struct GenerateConfig {
     base: PathBuf,
     patched: PathBuf,
@@ -18,7 +37,16 @@ struct GenerateConfig {
     level: Level,
     force: bool,
     checksum: bool,
+    hash: core::format::HashAlgorithm,
+    compression: core::codec::Compression,
     reverse: bool,
+    sign: Option<PathBuf>,
+    meta: Vec<String>,
+    meta_file: Option<PathBuf>,
+    patch_format: core::format::PatchFormat,
+    format: OutputFormat,
+    output_dir: Option<PathBuf>,
+    suffix: String,
 }
 
 struct ApplyConfig {
@@ -30,6 +58,61 @@ struct ApplyConfig {
     dry_run: bool,
     reverse: bool,
     verify: bool,
+    in_place: bool,
+    require_signature: Option<PathBuf>,
+    expect_meta: Vec<String>,
+    format: OutputFormat,
+    output_dir: Option<PathBuf>,
+    suffix: String,
+    preview: core::preview::PreviewOptions,
+}
+
+struct DiffConfig {
+    old: PathBuf,
+    new: PathBuf,
+    context: usize,
+    preview: core::preview::PreviewOptions,
+    pager: bool,
+    level: Level,
+    format: OutputFormat,
+}
+
+struct ComposeConfig {
+    patches: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    base: Option<PathBuf>,
+    level: Level,
+    force: bool,
+    format: OutputFormat,
+    output_dir: Option<PathBuf>,
+}
+
+struct UpgradeConfig {
+    base: PathBuf,
+    to: String,
+    store: PathBuf,
+    output: Option<PathBuf>,
+    dry_run: bool,
+    level: Level,
+    force: bool,
+    format: OutputFormat,
+    output_dir: Option<PathBuf>,
+    suffix: String,
+}
+
+struct ConvertConfig {
+    patch: PathBuf,
+    output: Option<PathBuf>,
+    to: core::format::PatchFormat,
+    files: Option<(PathBuf, PathBuf)>,
+    hash: core::format::HashAlgorithm,
+    compression: Option<core::codec::Compression>,
+    meta: Vec<String>,
+    meta_file: Option<PathBuf>,
+    level: Level,
+    force: bool,
+    format: OutputFormat,
+    output_dir: Option<PathBuf>,
 }
 
 struct ApplyResult<'a> {
@@ -39,13 +122,53 @@ struct ApplyResult<'a> {
     patch_size: u64,
     output_size: u64,
     dry_run: bool,
+    input_mode: String,
     changes: Option<&'a [core::preview::ByteChange]>,
+    max_changes: usize,
+}
+
+/// Exit code for unparsable command lines
+const USAGE_EXIT_CODE: i32 = 2;
+
+fn main() {
+    let opts: Opts = match facet_args::from_std_args() {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{:?}", miette::Report::from(err));
+            std::process::exit(USAGE_EXIT_CODE);
+        }
+    };
+    let format = opts
+        .format
+        .as_deref()
+        .map(str::parse::<OutputFormat>)
+        .transpose();
+    let json = matches!(format, Ok(Some(OutputFormat::Json)));
+    let result = format.and_then(|format| run(opts.cmd, format.unwrap_or_default()));
+
+    // Map failures to their documented exit code, JSON mode reports them on stdout
+    if let Err(err) = result {
+        let code = err.kind.exit_code();
+        if json {
+            // A failed verify already printed its report
+            if !matches!(err.kind, RustineErrorKind::VerificationFailed { .. }) {
+                ui::report::print(&ui::report::ErrorReport::from(&err));
+            }
+        } else {
+            eprintln!("{:?}", miette::Report::new(err));
+        }
+        std::process::exit(code.into());
+    }
 }
 
-fn main() -> miette::Result<()> {
-    let opts: Opts = facet_args::from_std_args()?;
+fn run(cmd: Command, format: OutputFormat) -> Result<()> {
+    // Defaults from rustine.toml, help works even with a broken one
+    let settings = match cmd {
+        Command::Help { .. } => Config::default(),
+        _ => Config::load()?,
+    };
 
-    match opts.cmd {
+    match cmd {
         rustine::cli::Command::Help { command } => {
             let cfg = facet_args::HelpConfig {
                 program_name: Some(env!("CARGO_PKG_NAME").to_string()),
@@ -78,16 +201,67 @@ fn main() -> miette::Result<()> {
             quiet,
             force,
             checksum,
+            no_checksum,
+            hash,
+            codec,
+            level,
             reverse,
+            no_reverse,
+            sign,
+            meta,
+            meta_file,
+            format: patch_format,
         } => {
+            let patch_format = patch_format
+                .as_deref()
+                .map(str::parse::<core::format::PatchFormat>)
+                .transpose()?
+                .unwrap_or(core::format::PatchFormat::Rustine2);
+            // Config defaults only shape native patches
+            let native = !patch_format.is_foreign();
+            // The configured level belongs to the configured codec
+            let level = match codec {
+                Some(_) => level,
+                None => level.or(settings.compression_level.filter(|_| native)),
+            };
+            let codec = codec
+                .or(settings.codec.clone().filter(|_| native))
+                .as_deref()
+                .map(str::parse::<core::codec::Codec>)
+                .transpose()?
+                .unwrap_or_default();
             let config = GenerateConfig {
                 base,
                 patched,
                 output,
-                level: Level::from_flags(verbose, quiet),
+                level: settings.level(verbose, quiet),
                 force,
-                checksum,
-                reverse,
+                checksum: toggle(
+                    "checksum",
+                    checksum,
+                    no_checksum,
+                    settings.checksum.filter(|_| native),
+                )?,
+                hash: hash
+                    .or(settings.hash.clone())
+                    .as_deref()
+                    .map(str::parse::<core::format::HashAlgorithm>)
+                    .transpose()?
+                    .unwrap_or_default(),
+                compression: core::codec::Compression::new(codec, level)?,
+                reverse: toggle(
+                    "reverse",
+                    reverse,
+                    no_reverse,
+                    settings.reverse.filter(|_| native),
+                )?,
+                sign,
+                meta,
+                meta_file,
+                patch_format,
+                format,
+                output_dir: settings.output_dir.clone(),
+                suffix: settings.patch_suffix().to_string(),
             };
             generate(config)?
         }
@@ -101,22 +275,192 @@ fn main() -> miette::Result<()> {
             quiet,
             force,
             verify,
+            no_verify,
+            in_place,
+            require_signature,
+            expect_meta,
+            gap,
+            min_region,
+            align,
+            max_regions,
         } => {
             let config = ApplyConfig {
                 base,
                 patch,
                 output,
-                level: Level::from_flags(verbose, quiet),
+                level: settings.level(verbose, quiet),
                 force,
                 dry_run,
                 reverse,
-                verify,
+                verify: toggle("verify", verify, no_verify, settings.verify)?,
+                in_place,
+                require_signature,
+                expect_meta,
+                format,
+                output_dir: settings.output_dir.clone(),
+                suffix: settings.patched_suffix().to_string(),
+                preview: preview_options(gap, min_region, align, max_regions),
+            };
+            apply(config).map_err(|err| would_not_apply(dry_run, err))?
+        }
+        rustine::cli::Command::Keygen {
+            output,
+            quiet,
+            force,
+        } => {
+            let level = settings.level(false, quiet);
+            keygen(output, force, level, format)?
+        }
+        rustine::cli::Command::Verify {
+            base,
+            patch,
+            target,
+            reverse,
+            verbose,
+            quiet,
+        } => {
+            let level = settings.level(verbose, quiet);
+            verify(base, patch, target, reverse, level, format)?
+        }
+        rustine::cli::Command::Diff {
+            old,
+            new,
+            context,
+            max_regions,
+            gap,
+            min_region,
+            align,
+            no_pager,
+            quiet,
+        } => {
+            let config = DiffConfig {
+                old,
+                new,
+                context: context.unwrap_or(DIFF_CONTEXT),
+                preview: preview_options(gap, min_region, align, max_regions),
+                pager: !no_pager,
+                level: settings.level(false, quiet),
+                format,
+            };
+            diff(config)?
+        }
+        rustine::cli::Command::Inspect {
+            patch,
+            verbose,
+            deep,
+            regions,
+        } => {
+            let level = settings.level(verbose, false);
+            let options = core::inspect::InspectOptions { deep, regions };
+            inspect(patch, options, level, format)?
+        }
+        rustine::cli::Command::Compose {
+            patches,
+            output,
+            base,
+            verbose,
+            quiet,
+            force,
+        } => {
+            let config = ComposeConfig {
+                patches,
+                output,
+                base,
+                level: settings.level(verbose, quiet),
+                force,
+                format,
+                output_dir: settings.output_dir.clone(),
+            };
+            compose(config)?
+        }
+        rustine::cli::Command::Upgrade {
+            base,
+            to,
+            store,
+            output,
+            dry_run,
+            verbose,
+            quiet,
+            force,
+        } => {
+            let Some(store) = store.or(settings.store.clone()) else {
+                return Err(RustineErrorKind::InvalidArguments {
+                    details: "no patch store, pass --store or set `store` in rustine.toml"
+                        .to_string(),
+                }
+                .into());
             };
-            apply(config)?
+            let config = UpgradeConfig {
+                base,
+                to,
+                store,
+                output,
+                dry_run,
+                level: settings.level(verbose, quiet),
+                force,
+                format,
+                output_dir: settings.output_dir.clone(),
+                suffix: settings.patched_suffix().to_string(),
+            };
+            upgrade(config)?
         }
-        rustine::cli::Command::Inspect { patch, verbose } => {
-            let level = Level::from_flags(verbose, false);
-            inspect(patch, level)?
+        rustine::cli::Command::Convert {
+            patch,
+            output,
+            to,
+            base,
+            target,
+            hash,
+            codec,
+            level,
+            meta,
+            meta_file,
+            verbose,
+            quiet,
+            force,
+        } => {
+            let files = match (base, target) {
+                (Some(base), Some(target)) => Some((base, target)),
+                (None, None) => None,
+                _ => {
+                    return Err(RustineErrorKind::InvalidArguments {
+                        details: "--base and --target must be given together".to_string(),
+                    }
+                    .into());
+                }
+            };
+            // Keep the input's compression unless asked otherwise
+            let compression = match (codec, level) {
+                (None, None) => None,
+                (codec, level) => Some(core::codec::Compression::new(
+                    codec
+                        .as_deref()
+                        .map(str::parse::<core::codec::Codec>)
+                        .transpose()?
+                        .unwrap_or_default(),
+                    level,
+                )?),
+            };
+            let config = ConvertConfig {
+                patch,
+                output,
+                to: to.parse()?,
+                files,
+                hash: hash
+                    .or(settings.hash.clone())
+                    .as_deref()
+                    .map(str::parse::<core::format::HashAlgorithm>)
+                    .transpose()?
+                    .unwrap_or_default(),
+                compression,
+                meta,
+                meta_file,
+                level: settings.level(verbose, quiet),
+                force,
+                format,
+                output_dir: settings.output_dir.clone(),
+            };
+            convert(config)?
         }
     }
 
@@ -127,14 +471,69 @@ fn generate(config: GenerateConfig) -> Result<()> {
     // Validate
     io::check::exists(&config.base)?;
     io::check::exists(&config.patched)?;
+    io::check::single_stdin(
+        [&config.base, &config.patched]
+            .into_iter()
+            .chain(&config.sign)
+            .chain(&config.meta_file)
+            .map(PathBuf::as_path),
+    )?;
+
+    // Raw deltas have no header to carry checksums, reverse patches or metadata
+    let foreign = config.patch_format.is_foreign();
+    if foreign
+        && (config.checksum
+            || config.reverse
+            || config.sign.is_some()
+            || !config.meta.is_empty()
+            || config.meta_file.is_some()
+            || config.compression != core::codec::Compression::default())
+    {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: format!(
+                "--checksum, --reverse, --sign, --meta and --codec need the rustine format, \
+                 {} patches cannot carry them",
+                config.patch_format.name()
+            ),
+        }
+        .into());
+    }
+
+    // Directory trees become an archive
+    if config.base.is_dir() || config.patched.is_dir() {
+        if foreign {
+            return Err(RustineErrorKind::FormatMismatch {
+                details: format!(
+                    "directories can only be diffed into an archive, not {}",
+                    config.patch_format.name()
+                ),
+            }
+            .into());
+        }
+        return generate_archive(config);
+    }
 
     // Create UI context
-    let ctx = Ctx::new(config.level);
+    let out_path = config.output.clone().unwrap_or_else(|| {
+        default_output(&config.base, config.output_dir.as_deref(), &config.suffix)
+    });
+    let ctx = ui_context(Some(&out_path), config.level, config.format)?;
 
-    // Read files (use streaming for large files)
-    let base_data = io::read_streaming(&config.base, &ctx)?;
-    let patched_data = io::read_streaming(&config.patched, &ctx)?;
+    // Load signing key first so a bad key fails fast
+    let signing_key = config
+        .sign
+        .as_deref()
+        .map(|path| load_key(path, &ctx, core::sign::parse_signing_key))
+        .transpose()?;
+
+    // Collect metadata
+    let metadata = build_metadata(config.meta_file.as_deref(), &config.meta, &ctx)?;
+
+    // Load files (memory-mapped when possible)
+    let base_data = io::load(&config.base, &ctx)?;
+    let patched_data = io::load(&config.patched, &ctx)?;
     let orig_size = patched_data.len() as u64;
+    let input_mode = input_mode(&[&base_data, &patched_data]);
 
     // Generate forward patch
     ctx.msg(&format!(
@@ -142,18 +541,53 @@ fn generate(config: GenerateConfig) -> Result<()> {
         io::filename(&config.base),
         io::filename(&config.patched)
     ));
-    let forward_patch = core::diff::create(&base_data, &patched_data)?;
 
-    // Build patch data with new format
-    let mut patch = core::format::PatchData::new(forward_patch);
+    // Raw deltas are written as they are
+    if foreign {
+        let patch = core::diff::create_as(config.patch_format, &base_data, &patched_data)?;
+        let mut out_file = io::create(&out_path, config.force, &ctx)?;
+        out_file.write_all(&patch)?;
+        out_file.commit()?;
+
+        show_gen_result(
+            &ctx,
+            &out_path,
+            orig_size,
+            patch.len() as u64,
+            false,
+            &input_mode,
+        );
+        return Ok(());
+    }
+
+    let forward_diff = core::diff::create(&base_data, &patched_data)?;
+    let forward_patch = config.compression.compress(&forward_diff)?;
+
+    // Build header with new format
+    let mut header = core::stream::PatchHeader {
+        compression: config.compression,
+        metadata,
+        has_reverse: config.reverse,
+        ..Default::default()
+    };
 
     // Add checksums if requested
     if config.checksum {
-        let base_hash = core::format::hash(&base_data);
-        let output_hash = core::format::hash(&patched_data);
-        patch = patch.with_checksums(base_hash, output_hash);
+        header.hash_algorithm = config.hash;
+        header.base_checksum = Some(core::format::hash(config.hash, &base_data));
+        header.output_checksum = Some(core::format::hash(config.hash, &patched_data));
     }
 
+    // Stream header and forward patch to output
+    let mut out_file = io::create(&out_path, config.force, &ctx)?;
+    let mut writer = match signing_key {
+        Some(key) => core::stream::PatchWriter::new_signed(&mut out_file, &header, key)?,
+        None => core::stream::PatchWriter::new(&mut out_file, &header)?,
+    };
+    writer.write_forward(forward_patch.len() as u64, &*forward_patch)?;
+    drop(forward_patch);
+    drop(forward_diff);
+
     // Add reverse patch if requested
     if config.reverse {
         ctx.msg(&format!(
@@ -161,27 +595,153 @@ fn generate(config: GenerateConfig) -> Result<()> {
             io::filename(&config.patched),
             io::filename(&config.base)
         ));
-        let reverse_patch = core::diff::create(&patched_data, &base_data)?;
-        patch = patch.with_reverse(reverse_patch);
+        let reverse_diff = core::diff::create(&patched_data, &base_data)?;
+        let reverse_patch = config.compression.compress(&reverse_diff)?;
+        writer.write_reverse(reverse_patch.len() as u64, &*reverse_patch)?;
+    }
+
+    let patch_size = writer.finish()?;
+    out_file.commit()?;
+
+    // Show results
+    show_gen_result(
+        &ctx,
+        &out_path,
+        orig_size,
+        patch_size,
+        config.reverse,
+        &input_mode,
+    );
+
+    Ok(())
+}
+
+fn generate_archive(config: GenerateConfig) -> Result<()> {
+    use core::archive::{ArchiveHeader, ArchiveWriter, Entry, EntryKind};
+
+    if !config.base.is_dir() || !config.patched.is_dir() {
+        return Err(RustineErrorKind::FormatMismatch {
+            details: "cannot diff a directory against a single file".to_string(),
+        }
+        .into());
     }
+    if config.reverse
+        || config.sign.is_some()
+        || config.meta_file.is_some()
+        || !config.meta.is_empty()
+    {
+        return Err(RustineErrorKind::FormatMismatch {
+            details: "--reverse, --sign and --meta are not supported for directory archives"
+                .to_string(),
+        }
+        .into());
+    }
+
+    // Create UI context
+    let out_path = config.output.clone().unwrap_or_else(|| {
+        default_output(&config.base, config.output_dir.as_deref(), &config.suffix)
+    });
+    let ctx = ui_context(Some(&out_path), config.level, config.format)?;
+
+    // Load both trees (memory-mapped when possible)
+    let load_tree = |root: &Path| -> Result<BTreeMap<String, io::Input>> {
+        ctx.msg(&format!("Scanning {}", io::filename(root)));
+        io::walk(root)?
+            .into_iter()
+            .map(|rel| {
+                let data = io::load(&root.join(&rel), &ctx)?;
+                Ok((rel, data))
+            })
+            .collect()
+    };
+    let base_tree = load_tree(&config.base)?;
+    let patched_tree = load_tree(&config.patched)?;
+    let orig_size = patched_tree.values().map(|data| data.len() as u64).sum();
+    let input_mode = input_mode(
+        &base_tree
+            .values()
+            .chain(patched_tree.values())
+            .collect::<Vec<_>>(),
+    );
+
+    // Hash every file to match unchanged and renamed entries
+    let hash_tree = |tree: &BTreeMap<String, io::Input>| -> BTreeMap<String, [u8; 32]> {
+        tree.iter()
+            .map(|(rel, data)| (rel.clone(), core::format::hash(config.hash, data)))
+            .collect()
+    };
+    let base_hashes = hash_tree(&base_tree);
+    let patched_hashes = hash_tree(&patched_tree);
+    let plan = core::archive::plan(&base_hashes, &patched_hashes);
+
+    // Stream entries to output
+    let mut out_file = io::create(&out_path, config.force, &ctx)?;
+    let header = ArchiveHeader {
+        hash_algorithm: config.hash,
+        compression: config.compression,
+        entry_count: plan.len() as u32,
+    };
+    let mut writer = ArchiveWriter::new(&mut out_file, &header)?;
+
+    for (rel, kind) in plan {
+        let diff;
+        let payload = match &kind {
+            EntryKind::Modified => {
+                ctx.msg(&format!("Generating patch for {}", rel));
+                diff = core::diff::create(&base_tree[&rel], &patched_tree[&rel])?;
+                config.compression.compress(&diff)?
+            }
+            EntryKind::Added => config.compression.compress(&patched_tree[&rel])?,
+            _ => Cow::Borrowed(&[][..]),
+        };
+        let base_checksum = match &kind {
+            EntryKind::Added => None,
+            EntryKind::Renamed { from } => base_hashes.get(from).copied(),
+            _ => base_hashes.get(&rel).copied(),
+        };
 
-    // Serialize patch
-    let patch_data = patch.serialize();
+        writer.write_entry(&Entry {
+            output_checksum: patched_hashes.get(&rel).copied(),
+            output_size: patched_tree.get(&rel).map_or(0, |data| data.len() as u64),
+            base_checksum,
+            path: rel,
+            kind,
+            payload: &payload,
+        })?;
+    }
 
-    // Write output
-    let out_path = config.output.unwrap_or_else(|| default_output(&config.base, ".patch"));
-    let patch_size = io::write(&out_path, &patch_data, config.force, &ctx)?;
+    let patch_size = writer.finish()?;
+    out_file.commit()?;
 
     // Show results
-    show_gen_result(&ctx, &out_path, orig_size, patch_size, config.reverse);
+    show_gen_result(&ctx, &out_path, orig_size, patch_size, false, &input_mode);
 
     Ok(())
 }
 
-fn show_gen_result(ctx: &Ctx, path: &Path, orig: u64, patch: u64, has_reverse: bool) {
+fn show_gen_result(
+    ctx: &Ctx,
+    path: &Path,
+    orig: u64,
+    patch: u64,
+    has_reverse: bool,
+    input_mode: &str,
+) {
     use ui::fmt;
     let reduction = fmt::reduce(orig, patch);
 
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::GenerateReport {
+            output: path.display().to_string(),
+            original_size: orig,
+            patch_size: patch,
+            reduction,
+            bidirectional: has_reverse,
+            input_mode: input_mode.to_string(),
+        });
+        return;
+    }
+
     match ctx.level() {
         Level::Quiet => {}
         Level::Normal => {
@@ -211,6 +771,7 @@ fn show_gen_result(ctx: &Ctx, path: &Path, orig: u64, patch: u64, has_reverse: b
             if has_reverse {
                 msg.push_str(&format!("\n   {} Bidirectional: yes", fmt::info()));
             }
+            msg.push_str(&format!("\n   {} Input mode:    {}", fmt::info(), input_mode));
             ctx.done(&msg);
         }
     }
@@ -221,24 +782,74 @@ fn apply(config: ApplyConfig) -> Result<()> {
     io::check::exists(&config.base)?;
     io::check::exists(&config.patch)?;
 
+    // Directory trees are rebuilt from an archive
+    if config.base.is_dir() {
+        return apply_archive(config);
+    }
+    io::check::single_stdin(
+        [&config.base, &config.patch]
+            .into_iter()
+            .chain(&config.require_signature)
+            .map(PathBuf::as_path),
+    )?;
+    if config.in_place && config.output.is_some() {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "--in-place rewrites the base file, it cannot be combined with --output"
+                .to_string(),
+        }
+        .into());
+    }
+    if config.in_place && io::is_stdio(&config.base) {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "--in-place needs a base file, not stdin".to_string(),
+        }
+        .into());
+    }
+
     // Create UI context
-    let ctx = Ctx::new(config.level);
+    let out_path = config.output.clone().unwrap_or_else(|| {
+        default_output(&config.base, config.output_dir.as_deref(), &config.suffix)
+    });
+    let ctx = ui_context(
+        (!config.dry_run).then_some(out_path.as_path()),
+        config.level,
+        config.format,
+    )?;
 
-    // Read files (use streaming for large files)
-    let base_data = io::read_streaming(&config.base, &ctx)?;
+    // Load base (memory-mapped when possible)
+    let base_data = io::load(&config.base, &ctx)?;
     let base_size = base_data.len() as u64;
-    let patch_file_data = io::read(&config.patch, &ctx)?;
+
+    // Load patch and borrow the sections without copying them
+    let patch_file_data = io::load(&config.patch, &ctx)?;
     let patch_size = patch_file_data.len() as u64;
+    let patch_data = core::format::PatchView::parse(&patch_file_data)?;
 
-    // Deserialize patch
-    let patch_data = core::format::PatchData::deserialize(&patch_file_data)?;
+    // Check signature before trusting anything else in the patch
+    if let Some(ref key_path) = config.require_signature {
+        let trusted = load_key(key_path, &ctx, core::sign::parse_verifying_key)?;
+        let signature = patch_data
+            .signature
+            .ok_or(RustineErrorKind::SignatureMissing)?;
+        ctx.msg(&format!("Verifying signature {}", signature.fingerprint()));
+        signature.verify(&patch_file_data, &trusted)?;
+    }
+
+    // Check expected metadata
+    if !config.expect_meta.is_empty() {
+        let meta = patch_data.meta()?;
+        for pair in &config.expect_meta {
+            let (key, value) = core::meta::Metadata::parse_pair(pair)?;
+            meta.expect(&key, &value)?;
+        }
+    }
 
     // Select which patch to use (forward or reverse)
     let (patch_to_apply, base_hash, output_hash) = if config.reverse {
-        if let Some(ref rev_patch) = patch_data.reverse_patch {
+        if let Some(rev_patch) = patch_data.reverse_patch {
             // When reversing: swap the checksums too
             (
-                rev_patch.as_slice(),
+                rev_patch,
                 patch_data.output_checksum,
                 patch_data.base_checksum,
             )
@@ -247,17 +858,36 @@ fn apply(config: ApplyConfig) -> Result<()> {
         }
     } else {
         (
-            patch_data.forward_patch.as_slice(),
+            patch_data.forward_patch,
             patch_data.base_checksum,
             patch_data.output_checksum,
         )
     };
 
+    // An interrupted in-place run left the base half patched
+    let in_place = config.in_place && !config.dry_run;
+    // Only bsdiff payloads have the control stream in-place writes are planned from
+    let bsdiff = matches!(
+        patch_data.format,
+        core::format::PatchFormat::Rustine2 | core::format::PatchFormat::Bsdiff4
+    );
+    if in_place && !bsdiff {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: format!(
+                "{} patches cannot be applied in place",
+                patch_data.format.name()
+            ),
+        }
+        .into());
+    }
+    let resuming = in_place && core::patch::journal_path(&config.base).exists();
+
     // Verify base file checksum if requested and available
     if config.verify
+        && !resuming
         && let Some(expected_hash) = base_hash {
             ctx.msg("Verifying base file checksum");
-            core::format::verify_hash(&base_data, &expected_hash)?;
+            core::format::verify_hash(patch_data.hash_algorithm, &base_data, &expected_hash)?;
         }
 
     // Apply patch
@@ -271,30 +901,73 @@ fn apply(config: ApplyConfig) -> Result<()> {
         io::filename(&config.base),
         if config.reverse { " (reverse)" } else { "" }
     ));
-    let result = core::patch::apply(&base_data, patch_to_apply)?;
+    let patch_to_apply = patch_data.compression.decompress(patch_to_apply)?;
+
+    // Rewrite the base file itself, without room for a separate output
+    if in_place {
+        drop(base_data);
+        if resuming {
+            ctx.msg("Resuming interrupted in-place run");
+        }
+        let result_size = core::patch::apply_in_place(&config.base, &patch_to_apply)?;
+
+        if config.verify
+            && let Some(expected_hash) = output_hash
+        {
+            ctx.msg("Verifying output checksum");
+            let result = io::load(&config.base, &ctx)?;
+            core::format::verify_hash(patch_data.hash_algorithm, &result, &expected_hash)?;
+        }
+
+        show_apply_result(ApplyResult {
+            ctx: &ctx,
+            path: Some(&config.base),
+            base_size,
+            patch_size,
+            output_size: result_size,
+            dry_run: false,
+            input_mode: "in place".to_string(),
+            changes: None,
+            max_changes: MAX_PREVIEW_CHANGES,
+        });
+        return Ok(());
+    }
+
+    let result = core::patch::apply_as(patch_data.format, &base_data, &patch_to_apply)?;
     let result_size = result.len() as u64;
 
     // Verify output checksum if requested and available
     if config.verify
         && let Some(expected_hash) = output_hash {
             ctx.msg("Verifying output checksum");
-            core::format::verify_hash(&result, &expected_hash)?;
+            core::format::verify_hash(patch_data.hash_algorithm, &result, &expected_hash)?;
         }
 
-    // Show preview if verbose
-    let changes = if config.level == Level::Verbose {
-        Some(core::preview::find_changes(&base_data, &result))
-    } else {
+    // Show preview if verbose or reporting JSON, aligned along the patch
+    let changes = if config.level != Level::Verbose && config.format != OutputFormat::Json {
         None
+    } else if !bsdiff {
+        Some(core::preview::find_changes_with(
+            &base_data,
+            &result,
+            &config.preview,
+        ))
+    } else {
+        let stream = core::control::ControlStream::decode(&patch_to_apply)?;
+        Some(core::preview::aligned_changes(
+            &stream,
+            &base_data,
+            &result,
+            &config.preview,
+        ))
     };
 
     // Write output (if not dry-run)
     let out_path = if config.dry_run {
         None
     } else {
-        let path = config.output.unwrap_or_else(|| default_output(&config.base, ".patched"));
-        io::write(&path, &result, config.force, &ctx)?;
-        Some(path)
+        io::write(&out_path, &result, config.force, &ctx)?;
+        Some(out_path)
     };
 
     // Show results
@@ -305,14 +978,222 @@ fn apply(config: ApplyConfig) -> Result<()> {
         patch_size,
         output_size: result_size,
         dry_run: config.dry_run,
+        input_mode: input_mode(&[&base_data, &patch_file_data]),
         changes: changes.as_deref(),
+        max_changes: config.preview.max_regions.unwrap_or(MAX_PREVIEW_CHANGES),
+    });
+
+    Ok(())
+}
+
+fn apply_archive(config: ApplyConfig) -> Result<()> {
+    use core::archive::{ArchiveView, EntryKind};
+
+    // Create UI context
+    let ctx = ui_context(None, config.level, config.format)?;
+
+    if config.in_place {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "directory archives cannot be applied in place".to_string(),
+        }
+        .into());
+    }
+
+    // Archives carry neither reverse patches, signatures nor metadata
+    if config.reverse {
+        return Err(RustineErrorKind::MissingReversePatch.into());
+    }
+    if config.require_signature.is_some() {
+        return Err(RustineErrorKind::SignatureMissing.into());
+    }
+    for pair in &config.expect_meta {
+        let (key, value) = core::meta::Metadata::parse_pair(pair)?;
+        core::meta::Metadata::default().expect(&key, &value)?;
+    }
+
+    // Load patch and borrow the entries without copying them
+    let patch_file_data = io::load(&config.patch, &ctx)?;
+    let patch_size = patch_file_data.len() as u64;
+    let archive = ArchiveView::parse(&patch_file_data)?;
+
+    let out_dir = if config.dry_run {
+        None
+    } else {
+        let dir = config.output.unwrap_or_else(|| {
+            default_output(&config.base, config.output_dir.as_deref(), &config.suffix)
+        });
+        if io::is_stdio(&dir) {
+            return Err(RustineErrorKind::InvalidArguments {
+                details: "directory archives rebuild a tree, they cannot be written to stdout"
+                    .to_string(),
+            }
+            .into());
+        }
+        io::check::can_write(&dir, config.force)?;
+        Some(dir)
+    };
+
+    // Rebuild and verify every entry into a staging directory first, so a
+    // failure leaves the output as it was and no write can clobber a base
+    // file that a later entry still reads
+    let stage = out_dir.as_deref().map(Staging::new).transpose()?;
+    let mut staged = Vec::new();
+    let mut stale = Vec::new();
+    let mut base_size = 0;
+    let mut output_size = 0;
+    for entry in &archive.entries {
+        let source = match &entry.kind {
+            EntryKind::Removed => {
+                stale.push(entry.path.as_str());
+                continue;
+            }
+            EntryKind::Added => None,
+            EntryKind::Renamed { from } => {
+                stale.push(from.as_str());
+                Some(from.as_str())
+            }
+            EntryKind::Unchanged | EntryKind::Modified => Some(entry.path.as_str()),
+        };
+
+        // Load base file and verify its checksum if requested
+        let base_data = source
+            .map(|rel| {
+                let path = config.base.join(rel);
+                io::check::exists(&path)?;
+                io::load(&path, &ctx)
+            })
+            .transpose()?;
+        let base_bytes = base_data.as_deref().unwrap_or_default();
+        base_size += base_bytes.len() as u64;
+        if config.verify
+            && let (Some(rel), Some(expected_hash)) = (source, entry.base_checksum)
+        {
+            ctx.msg(&format!("Verifying checksum of {}", rel));
+            core::format::verify_hash(archive.hash_algorithm, base_bytes, &expected_hash)?;
+        }
+
+        // Rebuild entry
+        ctx.msg(&format!(
+            "{} {}",
+            if config.dry_run {
+                "Verifying"
+            } else {
+                "Rebuilding"
+            },
+            entry.path
+        ));
+        let result: Cow<'_, [u8]> = match &entry.kind {
+            EntryKind::Added => archive.compression.decompress(entry.payload)?,
+            EntryKind::Modified => Cow::Owned(core::patch::apply(
+                base_bytes,
+                &archive.compression.decompress(entry.payload)?,
+            )?),
+            _ => Cow::Borrowed(base_bytes),
+        };
+        output_size += result.len() as u64;
+
+        // Verify output checksum if requested and available
+        if config.verify
+            && let Some(expected_hash) = entry.output_checksum
+        {
+            core::format::verify_hash(archive.hash_algorithm, &result, &expected_hash)?;
+        }
+
+        if let Some(stage) = &stage {
+            let path = stage.path.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            io::write(&path, &result, true, &ctx)?;
+            staged.push(entry.path.as_str());
+        }
+    }
+
+    // Publish: move staged files into place, then drop removed files and
+    // rename sources left over from an existing tree
+    if let (Some(dir), Some(stage)) = (&out_dir, &stage) {
+        for rel in &staged {
+            let (from, to) = (stage.path.join(rel), dir.join(rel));
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // A replaced file keeps its permissions
+            if let Ok(target) = std::fs::metadata(&to) {
+                std::fs::set_permissions(&from, target.permissions())?;
+            }
+            std::fs::rename(&from, &to)?;
+        }
+        let written: BTreeSet<&str> = staged.into_iter().collect();
+        for rel in stale.into_iter().filter(|rel| !written.contains(rel)) {
+            let path = dir.join(rel);
+            if path.is_file() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    // Show results
+    show_apply_result(ApplyResult {
+        ctx: &ctx,
+        path: out_dir.as_deref(),
+        base_size,
+        patch_size,
+        output_size,
+        dry_run: config.dry_run,
+        input_mode: input_mode(&[&patch_file_data]),
+        changes: None,
+        max_changes: MAX_PREVIEW_CHANGES,
     });
 
     Ok(())
 }
 
+/// Hidden sibling of an output directory, removed with everything left in it
+struct Staging {
+    path: PathBuf,
+}
+
+impl Staging {
+    fn new(dir: &Path) -> Result<Self> {
+        let parent = dir
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let path = parent.join(format!(
+            ".{}.{}.stage",
+            io::filename(dir),
+            std::process::id()
+        ));
+        // Only a crashed run with the same PID can have left it
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
 fn show_apply_result(result: ApplyResult) {
     use ui::fmt;
+
+    if result.ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::ApplyReport {
+            output: result.path.map(|path| path.display().to_string()),
+            base_size: result.base_size,
+            patch_size: result.patch_size,
+            output_size: result.output_size,
+            dry_run: result.dry_run,
+            input_mode: result.input_mode,
+            changes: result.changes.unwrap_or_default(),
+        });
+        return;
+    }
     match result.ctx.level() {
         Level::Quiet => {}
         Level::Normal => {
@@ -358,6 +1239,11 @@ fn show_apply_result(result: ApplyResult) {
                     fmt::path(result.path.unwrap().display())
                 )
             };
+            msg.push_str(&format!(
+                "\n   {} Input mode:  {}",
+                fmt::info(),
+                result.input_mode
+            ));
 
             // Add change preview if available
             if let Some(changes) = result.changes {
@@ -368,42 +1254,7 @@ fn show_apply_result(result: ApplyResult) {
                 ));
 
                 // Show first few changes in detail
-                for (i, change) in changes.iter().take(MAX_PREVIEW_CHANGES).enumerate() {
-                    if i == 0 {
-                        msg.push('\n');
-                    }
-                    msg.push_str(&format!(
-                        "\n   {} Offset 0x{:08x}:",
-                        fmt::info(),
-                        change.offset
-                    ));
-
-                    if !change.old_bytes.is_empty() {
-                        msg.push_str(&format!(
-                            "\n      - {}",
-                            core::preview::format_hex_dump(&change.old_bytes, 16)
-                        ));
-                    }
-                    if !change.new_bytes.is_empty() {
-                        msg.push_str(&format!(
-                            "\n      + {}",
-                            core::preview::format_hex_dump(&change.new_bytes, 16)
-                        ));
-                    }
-                }
-
-                if changes.len() > MAX_PREVIEW_CHANGES {
-                    msg.push_str(&format!(
-                        "\n   {} ... and {} more change region{}",
-                        fmt::info(),
-                        changes.len() - MAX_PREVIEW_CHANGES,
-                        if changes.len() - MAX_PREVIEW_CHANGES == 1 {
-                            ""
-                        } else {
-                            "s"
-                        }
-                    ));
-                }
+                msg.push_str(&change_details(changes, result.max_changes));
             }
 
             result.ctx.done(&msg);
@@ -411,23 +1262,792 @@ fn show_apply_result(result: ApplyResult) {
     }
 }
 
-fn default_output(base: &Path, ext: &str) -> PathBuf {
-    PathBuf::from(format!("{}{}", io::filename(base), ext))
+/// Flag and its `--no-` counterpart over a config default, the flags win
+fn toggle(name: &str, on: bool, off: bool, setting: Option<bool>) -> Result<bool> {
+    match (on, off) {
+        (true, true) => Err(RustineErrorKind::InvalidArguments {
+            details: format!("--{name} and --no-{name} cannot be used together"),
+        }
+        .into()),
+        (false, false) => Ok(setting.unwrap_or_default()),
+        (on, _) => Ok(on),
+    }
 }
 
-fn inspect(patch: PathBuf, level: Level) -> Result<()> {
-    // Validate
-    io::check::exists(&patch)?;
-
-    // Create UI context
-    let ctx = Ctx::new(level);
+/// Preview grouping from command-line flags
+fn preview_options(
+    gap: Option<usize>,
+    min_region: Option<usize>,
+    align: Option<usize>,
+    max_regions: Option<usize>,
+) -> core::preview::PreviewOptions {
+    let defaults = core::preview::PreviewOptions::default();
+    core::preview::PreviewOptions {
+        gap_tolerance: gap.unwrap_or(defaults.gap_tolerance),
+        min_region: min_region.unwrap_or(defaults.min_region),
+        alignment: align.unwrap_or(defaults.alignment),
+        max_regions,
+    }
+}
 
-    // Read patch
-    let patch_data = io::read(&patch, &ctx)?;
+/// Hex dump of the first `max` changes
+fn change_details(changes: &[core::preview::ByteChange], max: usize) -> String {
+    use core::preview::ChangeKind;
+    use ui::fmt;
 
-    // Inspect patch
-    ctx.msg(&format!("Inspecting patch {}", io::filename(&patch)));
-    let info = core::inspect::inspect(&patch_data)?;
+    let mut msg = String::new();
+    for (i, change) in changes.iter().take(max).enumerate() {
+        if i == 0 {
+            msg.push('\n');
+        }
+        match change.kind {
+            ChangeKind::Replaced => msg.push_str(&format!(
+                "\n   {} Offset 0x{:08x}:",
+                fmt::info(),
+                change.offset
+            )),
+            kind => msg.push_str(&format!(
+                "\n   {} {} 0x{:08x} → 0x{:08x}:",
+                fmt::info(),
+                kind.name(),
+                change.offset,
+                change.new_offset
+            )),
+        }
+
+        if !change.old_bytes.is_empty() {
+            msg.push_str(&format!(
+                "\n      - {}",
+                core::preview::format_hex_dump(&change.old_bytes, 16)
+            ));
+        }
+        if !change.new_bytes.is_empty() {
+            msg.push_str(&format!(
+                "\n      + {}",
+                core::preview::format_hex_dump(&change.new_bytes, 16)
+            ));
+        }
+    }
+
+    if changes.len() > max {
+        msg.push_str(&format!(
+            "\n   {} ... and {} more change region{}",
+            fmt::info(),
+            changes.len() - max,
+            if changes.len() - max == 1 { "" } else { "s" }
+        ));
+    }
+    msg
+}
+
+fn compose(config: ComposeConfig) -> Result<()> {
+    use ui::fmt;
+
+    // Validate
+    let Some(first) = config.patches.first() else {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "compose needs at least one patch".to_string(),
+        }
+        .into());
+    };
+    for path in config.patches.iter().chain(&config.base) {
+        io::check::exists(path)?;
+    }
+    io::check::single_stdin(
+        config
+            .patches
+            .iter()
+            .chain(&config.base)
+            .map(PathBuf::as_path),
+    )?;
+
+    // Create UI context
+    let out_path = config
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(first, config.output_dir.as_deref(), ".composed"));
+    let ctx = ui_context(Some(&out_path), config.level, config.format)?;
+
+    // Load inputs (memory-mapped when possible)
+    let patches = config
+        .patches
+        .iter()
+        .map(|path| io::load(path, &ctx))
+        .collect::<Result<Vec<_>>>()?;
+    let base = config
+        .base
+        .as_deref()
+        .map(|path| io::load(path, &ctx))
+        .transpose()?;
+    let input_size = patches.iter().map(|patch| patch.len() as u64).sum();
+
+    ctx.msg(&format!("Composing {} patches", patches.len()));
+    let composed = core::compose::compose(
+        &patches.iter().map(|patch| &patch[..]).collect::<Vec<_>>(),
+        base.as_deref(),
+    )?;
+    let patch_size = io::write(&out_path, &composed.data, config.force, &ctx)?;
+
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::ComposeReport {
+            output: out_path.display().to_string(),
+            patches: patches.len(),
+            input_size,
+            patch_size,
+            rebuilt: composed.rebuilt,
+            bidirectional: composed.has_reverse,
+            lost: composed.lost.iter().map(|lost| lost.to_string()).collect(),
+        });
+        return Ok(());
+    }
+
+    for lost in &composed.lost {
+        ctx.warn(&format!(
+            "the {} of the chain was dropped, pass --base to rebuild it",
+            lost
+        ));
+    }
+
+    let reverse_msg = if composed.has_reverse {
+        " (bidirectional)"
+    } else {
+        ""
+    };
+    match ctx.level() {
+        Level::Quiet => {}
+        Level::Normal => ctx.done(&format!(
+            "{} Composed {} patch{} into {}{} ({})",
+            fmt::ok(),
+            patches.len(),
+            if patches.len() == 1 { "" } else { "es" },
+            fmt::path(out_path.display()),
+            reverse_msg,
+            fmt::bytes(patch_size)
+        )),
+        Level::Verbose => {
+            let method = if composed.rebuilt {
+                "rebuilt from the base"
+            } else {
+                "merged control streams"
+            };
+            ctx.done(&format!(
+                "{} Composed patch\n   {} Patches:    {} ({})\n   {} Patch size: {}\n   {} Method:     {}\n   {} Saved to:   {}",
+                fmt::ok(),
+                fmt::info(),
+                patches.len(),
+                fmt::bytes(input_size),
+                fmt::info(),
+                fmt::bytes(patch_size),
+                fmt::info(),
+                method,
+                fmt::info(),
+                fmt::path(out_path.display())
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn upgrade(config: UpgradeConfig) -> Result<()> {
+    use ui::fmt;
+
+    // Validate
+    io::check::exists(&config.base)?;
+    io::check::exists(&config.store)?;
+
+    // Create UI context
+    let out_path = config.output.clone().unwrap_or_else(|| {
+        default_output(&config.base, config.output_dir.as_deref(), &config.suffix)
+    });
+    let ctx = ui_context(
+        (!config.dry_run).then_some(out_path.as_path()),
+        config.level,
+        config.format,
+    )?;
+
+    // Index every patch with both checksums by its header, skipping other files
+    ctx.msg(&format!("Indexing {}", io::filename(&config.store)));
+    let mut store = core::store::PatchStore::default();
+    for rel in io::walk(&config.store)? {
+        let path = config.store.join(rel);
+        match io::open(&path, &ctx) {
+            Ok(file) => {
+                store.insert(path, file);
+            }
+            Err(err) if ctx.level() == Level::Verbose => {
+                ctx.warn(&format!("Skipped {}: {}", path.display(), err));
+            }
+            Err(_) => {}
+        }
+    }
+
+    // Find the cheapest chain
+    let base_data = io::load(&config.base, &ctx)?;
+    ctx.msg(&format!("Finding a chain to {}", config.to));
+    let Some(chain) = store.route(&base_data, &config.to) else {
+        return Err(RustineErrorKind::NoUpgradePath { target: config.to }.into());
+    };
+    let patch_size = chain.iter().map(|step| step.size()).sum();
+
+    // Apply step by step, checking the checksums around each patch; a base
+    // already at the target is left alone
+    let write = !config.dry_run && !chain.is_empty();
+    let mut output_size = None;
+    if write {
+        let mut data: Cow<[u8]> = Cow::Borrowed(&base_data);
+        for (index, step) in chain.iter().enumerate() {
+            ctx.msg(&format!(
+                "Applying {} ({}/{})",
+                io::filename(&step.patch.path),
+                index + 1,
+                chain.len()
+            ));
+            let patch = io::load(&step.patch.path, &ctx)?;
+            data = Cow::Owned(step.apply(&data, &patch).map_err(|e| {
+                RustineError::new(
+                    e.kind,
+                    RustineErrorContext::default().with_path(step.patch.path.clone()),
+                )
+            })?);
+        }
+        output_size = Some(io::write(&out_path, &data, config.force, &ctx)?);
+    }
+
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::UpgradeReport {
+            output: write.then(|| out_path.display().to_string()),
+            base_size: base_data.len() as u64,
+            output_size,
+            patch_size,
+            dry_run: config.dry_run,
+            steps: chain
+                .iter()
+                .map(|step| ui::report::UpgradeStep {
+                    patch: step.patch.path.display().to_string(),
+                    reverse: step.reverse,
+                    size: step.size(),
+                })
+                .collect(),
+        });
+        return Ok(());
+    }
+
+    let steps = chain
+        .iter()
+        .map(|step| {
+            format!(
+                "\n   {} {} ({}){}",
+                fmt::info(),
+                fmt::path(step.patch.path.display()),
+                fmt::bytes(step.size()),
+                if step.reverse { ", reversed" } else { "" }
+            )
+        })
+        .collect::<String>();
+    let patches = format!(
+        "{} patch{}",
+        chain.len(),
+        if chain.len() == 1 { "" } else { "es" }
+    );
+    let summary = match (chain.len(), config.dry_run) {
+        (0, _) => format!(
+            "{} Base already matches {}, nothing written",
+            fmt::ok(),
+            config.to
+        ),
+        (_, true) => format!(
+            "{} Would apply {} ({}) to reach {}",
+            fmt::ok(),
+            patches,
+            fmt::bytes(patch_size),
+            config.to
+        ),
+        (_, false) => format!(
+            "{} Applied {} ({}), wrote {}",
+            fmt::ok(),
+            patches,
+            fmt::bytes(patch_size),
+            fmt::path(out_path.display())
+        ),
+    };
+    match ctx.level() {
+        Level::Quiet => {}
+        Level::Normal if !config.dry_run => ctx.done(&summary),
+        _ => ctx.done(&format!("{}{}", summary, steps)),
+    }
+
+    Ok(())
+}
+
+fn convert(config: ConvertConfig) -> Result<()> {
+    use ui::fmt;
+
+    // Validate
+    let (base, target) = config.files.clone().unzip();
+    for path in [&config.patch].into_iter().chain(&base).chain(&target) {
+        io::check::exists(path)?;
+    }
+    io::check::single_stdin(
+        [&config.patch]
+            .into_iter()
+            .chain(&base)
+            .chain(&target)
+            .chain(&config.meta_file)
+            .map(PathBuf::as_path),
+    )?;
+
+    // Create UI context
+    let suffix = format!(".{}", config.to.name().to_lowercase());
+    let out_path = config
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(&config.patch, config.output_dir.as_deref(), &suffix));
+    let ctx = ui_context(Some(&out_path), config.level, config.format)?;
+
+    // Collect metadata
+    let metadata = build_metadata(config.meta_file.as_deref(), &config.meta, &ctx)?
+        .map(|toml| core::meta::Metadata::parse(&toml))
+        .transpose()?;
+
+    // Load inputs (memory-mapped when possible)
+    let patch_data = io::load(&config.patch, &ctx)?;
+    let files = match (&base, &target) {
+        (Some(base), Some(target)) => Some((io::load(base, &ctx)?, io::load(target, &ctx)?)),
+        _ => None,
+    };
+
+    ctx.msg(&format!(
+        "Converting {} to {}",
+        io::filename(&config.patch),
+        config.to.name()
+    ));
+    let converted = core::convert::convert(
+        &patch_data,
+        config.to,
+        core::convert::ConvertOptions {
+            compression: config.compression,
+            files: files
+                .as_ref()
+                .map(|(base, target)| (&base[..], &target[..])),
+            hash: config.hash,
+            metadata,
+        },
+    )?;
+    let patch_size = io::write(&out_path, &converted.data, config.force, &ctx)?;
+
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::ConvertReport {
+            output: out_path.display().to_string(),
+            from: converted.from.name().to_string(),
+            to: config.to.name().to_string(),
+            input_size: patch_data.len() as u64,
+            patch_size,
+            lost: converted.lost.iter().map(|lost| lost.to_string()).collect(),
+        });
+        return Ok(());
+    }
+
+    for lost in &converted.lost {
+        ctx.warn(&format!(
+            "{} cannot carry the {} of the input, it was dropped",
+            config.to.name(),
+            lost
+        ));
+    }
+
+    match ctx.level() {
+        Level::Quiet => {}
+        Level::Normal => ctx.done(&format!(
+            "{} Wrote {} {} patch to {}",
+            fmt::ok(),
+            fmt::bytes(patch_size),
+            config.to.name(),
+            fmt::path(out_path.display())
+        )),
+        Level::Verbose => ctx.done(&format!(
+            "{} Converted patch\n   {} From:       {} ({})\n   {} To:         {} ({})\n   {} Saved to:   {}",
+            fmt::ok(),
+            fmt::info(),
+            converted.from.name(),
+            fmt::bytes(patch_data.len() as u64),
+            fmt::info(),
+            config.to.name(),
+            fmt::bytes(patch_size),
+            fmt::info(),
+            fmt::path(out_path.display())
+        )),
+    }
+
+    Ok(())
+}
+
+fn keygen(output: Option<PathBuf>, force: bool, level: Level, format: OutputFormat) -> Result<()> {
+    use ui::fmt;
+
+    // Create UI context
+    let ctx = ui_context(None, level, format)?;
+
+    let prefix = output.unwrap_or_else(|| PathBuf::from("rustine"));
+    let secret_path = PathBuf::from(format!("{}.key", prefix.display()));
+    let public_path = PathBuf::from(format!("{}.pub", prefix.display()));
+
+    ctx.msg("Generating Ed25519 key pair");
+    let key = core::sign::generate_key()?;
+    let public_key = key.verifying_key().to_bytes();
+
+    io::write_secret(
+        &secret_path,
+        core::sign::encode_key(&key.to_bytes()).as_bytes(),
+        force,
+        &ctx,
+    )?;
+    io::write(
+        &public_path,
+        core::sign::encode_key(&public_key).as_bytes(),
+        force,
+        &ctx,
+    )?;
+
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::KeygenReport {
+            secret_key: secret_path.display().to_string(),
+            public_key: public_path.display().to_string(),
+            fingerprint: core::sign::fingerprint(&public_key),
+        });
+        return Ok(());
+    }
+
+    ctx.done(&format!(
+        "{} Wrote {} and {} (fingerprint {})",
+        fmt::ok(),
+        fmt::path(secret_path.display()),
+        fmt::path(public_path.display()),
+        core::sign::fingerprint(&public_key)
+    ));
+
+    Ok(())
+}
+
+/// Merge metadata file and key=value pairs into TOML, if any were given
+fn build_metadata(file: Option<&Path>, pairs: &[String], ctx: &Ctx) -> Result<Option<String>> {
+    if file.is_none() && pairs.is_empty() {
+        return Ok(None);
+    }
+
+    let mut meta = match file {
+        Some(path) => {
+            io::check::exists(path)?;
+            let text = io::read(path, ctx)?;
+            core::meta::Metadata::parse(&String::from_utf8_lossy(&text)).map_err(|e| {
+                RustineError::new(
+                    e.kind,
+                    RustineErrorContext::default().with_path(path.to_path_buf()),
+                )
+            })?
+        }
+        None => core::meta::Metadata::default(),
+    };
+
+    // Pairs given on the command line win over the file
+    for pair in pairs {
+        let (key, value) = core::meta::Metadata::parse_pair(pair)?;
+        meta.set(key, value);
+    }
+    meta.stamp();
+
+    meta.to_toml().map(Some)
+}
+
+/// Read and parse a key file, pointing errors at the file
+fn load_key<T>(path: &Path, ctx: &Ctx, parse: fn(&str) -> Result<T>) -> Result<T> {
+    io::check::exists(path)?;
+    let text = io::read(path, ctx)?;
+    parse(&String::from_utf8_lossy(&text)).map_err(|e| {
+        RustineError::new(
+            e.kind,
+            RustineErrorContext::default().with_path(path.to_path_buf()),
+        )
+    })
+}
+
+/// Describe how inputs were loaded, collapsing when they agree
+fn input_mode(inputs: &[&io::Input]) -> String {
+    let modes: Vec<&str> = inputs.iter().map(|input| input.mode()).collect();
+    if modes.windows(2).all(|w| w[0] == w[1]) {
+        modes.first().copied().unwrap_or_default().to_string()
+    } else {
+        modes.join(" / ")
+    }
+}
+
+/// Report a dry run rejected by the base or the patch contents as such
+fn would_not_apply(dry_run: bool, err: RustineError) -> RustineError {
+    let rejected = matches!(
+        err.kind,
+        RustineErrorKind::ChecksumMismatch { .. }
+            | RustineErrorKind::PatchFailed { .. }
+            | RustineErrorKind::MissingReversePatch
+            | RustineErrorKind::FormatMismatch { .. }
+            | RustineErrorKind::MetadataMismatch { .. }
+    );
+    if dry_run && rejected {
+        RustineErrorKind::WouldNotApply {
+            source: Box::new(err),
+        }
+        .into()
+    } else {
+        err
+    }
+}
+
+/// UI context for a command, keeping stdout clean for data or JSON reports
+fn ui_context(output: Option<&Path>, level: Level, format: OutputFormat) -> Result<Ctx> {
+    let to_stdout = output.is_some_and(io::is_stdio);
+    match format {
+        OutputFormat::Json if to_stdout => Err(RustineErrorKind::InvalidArguments {
+            details: "--format json prints its report on stdout, write the output to a file"
+                .to_string(),
+        }
+        .into()),
+        OutputFormat::Json => Ok(Ctx::json()),
+        OutputFormat::Text if to_stdout => Ok(Ctx::headless(level)),
+        OutputFormat::Text => Ok(Ctx::new(level)),
+    }
+}
+
+/// Output in the configured directory (working directory by default), or stdout when reading stdin
+fn default_output(base: &Path, dir: Option<&Path>, suffix: &str) -> PathBuf {
+    if io::is_stdio(base) {
+        return PathBuf::from(io::STDIO);
+    }
+    let name = format!("{}{}", io::filename(base), suffix);
+    match dir {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+fn verify(
+    base: PathBuf,
+    patch: PathBuf,
+    target: Option<PathBuf>,
+    reverse: bool,
+    level: Level,
+    format: OutputFormat,
+) -> Result<()> {
+    // Validate
+    io::check::exists(&base)?;
+    io::check::exists(&patch)?;
+    if let Some(ref target) = target {
+        io::check::exists(target)?;
+    }
+    if base.is_dir() {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "verify checks file patches, directory archives are not supported".to_string(),
+        }
+        .into());
+    }
+    io::check::single_stdin(
+        [&base, &patch]
+            .into_iter()
+            .chain(&target)
+            .map(PathBuf::as_path),
+    )?;
+
+    // Create UI context
+    let ctx = ui_context(None, level, format)?;
+
+    // Load inputs (memory-mapped when possible)
+    let base_data = io::load(&base, &ctx)?;
+    let patch_data = io::load(&patch, &ctx)?;
+    let target_data = target
+        .as_ref()
+        .map(|path| io::load(path, &ctx))
+        .transpose()?;
+
+    // Run every check, nothing is written
+    ctx.msg(&format!(
+        "Verifying {} against {}{}",
+        io::filename(&patch),
+        io::filename(&base),
+        if reverse { " (reverse)" } else { "" }
+    ));
+    let report = core::verify::verify(&base_data, &patch_data, target_data.as_deref(), reverse);
+
+    // Show results
+    show_verify_result(&ctx, &report);
+
+    report.into_result().map(drop)
+}
+
+fn show_verify_result(ctx: &Ctx, report: &core::verify::VerifyReport) {
+    use core::verify::Status;
+    use ui::fmt;
+
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(report);
+        return;
+    }
+
+    let mut msg = match (report.passed, report.output_size) {
+        (true, Some(size)) => format!(
+            "{} Patch verified {} output size",
+            fmt::ok(),
+            fmt::bytes(size)
+        ),
+        _ => format!("{} Patch verification failed", fmt::fail()),
+    };
+    match ctx.level() {
+        Level::Quiet => return,
+        Level::Normal if report.passed => {
+            ctx.done(&msg);
+            return;
+        }
+        // Failures list every check, so it is clear which one failed
+        _ => {}
+    }
+
+    for check in &report.checks {
+        let marker = match check.status {
+            Status::Pass => fmt::ok(),
+            Status::Fail => fmt::fail(),
+            Status::Skip => fmt::skip(),
+        };
+        msg.push_str(&format!(
+            "\n   {} {:<16} {}",
+            marker,
+            format!("{}:", check.name),
+            check.details
+        ));
+    }
+    ctx.done(&msg);
+}
+
+fn diff(config: DiffConfig) -> Result<()> {
+    // Validate
+    io::check::exists(&config.old)?;
+    io::check::exists(&config.new)?;
+    if config.old.is_dir() || config.new.is_dir() {
+        return Err(RustineErrorKind::InvalidArguments {
+            details: "diff compares two files, not directories".to_string(),
+        }
+        .into());
+    }
+    io::check::single_stdin([config.old.as_path(), config.new.as_path()])?;
+
+    // Create UI context
+    let ctx = ui_context(None, config.level, config.format)?;
+
+    // Load both files (memory-mapped when possible)
+    let old_data = io::load(&config.old, &ctx)?;
+    let new_data = io::load(&config.new, &ctx)?;
+
+    // Align through a bsdiff of the two files
+    ctx.msg(&format!(
+        "Comparing {} with {}",
+        io::filename(&config.old),
+        io::filename(&config.new)
+    ));
+    let changes = core::preview::changes_between(&old_data, &new_data, &config.preview)?;
+
+    // Show results
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(&ui::report::DiffReport {
+            old_size: old_data.len() as u64,
+            new_size: new_data.len() as u64,
+            changes: &changes,
+        });
+        return Ok(());
+    }
+    if ctx.level() != Level::Quiet {
+        let msg = diff_listing(&config, &old_data, &new_data, &changes);
+        ctx.clear();
+        if config.pager {
+            ui::pager::page(&msg);
+        } else {
+            println!("{}", msg);
+        }
+    }
+
+    Ok(())
+}
+
+/// Summary plus a side-by-side hex dump of each change
+fn diff_listing(
+    config: &DiffConfig,
+    old: &[u8],
+    new: &[u8],
+    changes: &[core::preview::ByteChange],
+) -> String {
+    use core::preview::ChangeKind;
+    use ui::{fmt, hex::Side};
+
+    if changes.is_empty() {
+        return format!("{} Files are identical", fmt::ok());
+    }
+    let mut msg = format!("{} {}", fmt::ok(), core::preview::preview_summary(changes));
+
+    let max_regions = config.preview.max_regions.unwrap_or(MAX_DIFF_CHANGES);
+    for change in changes.iter().take(max_regions) {
+        msg.push_str(&format!(
+            "\n\n   {} {} 0x{:08x} → 0x{:08x}, {} → {}",
+            fmt::info(),
+            change.kind.name(),
+            change.offset,
+            change.new_offset,
+            fmt::bytes(change.old_bytes.len() as u64),
+            fmt::bytes(change.new_bytes.len() as u64)
+        ));
+
+        let old_side = Side::around(old, change.offset, change.old_bytes.len(), config.context);
+        let new_side = Side::around(
+            new,
+            change.new_offset,
+            change.new_bytes.len(),
+            config.context,
+        );
+        msg.push_str(&ui::hex::side_by_side(
+            &old_side,
+            &new_side,
+            change.kind == ChangeKind::Replaced,
+            MAX_DIFF_ROWS,
+        ));
+    }
+
+    if changes.len() > max_regions {
+        msg.push_str(&format!(
+            "\n\n   {} ... and {} more change region{}, raise --max-regions to see them",
+            fmt::info(),
+            changes.len() - max_regions,
+            if changes.len() - max_regions == 1 {
+                ""
+            } else {
+                "s"
+            }
+        ));
+    }
+    msg
+}
+
+fn inspect(
+    patch: PathBuf,
+    options: core::inspect::InspectOptions,
+    level: Level,
+    format: OutputFormat,
+) -> Result<()> {
+    // Validate
+    io::check::exists(&patch)?;
+
+    // Create UI context
+    let ctx = ui_context(None, level, format)?;
+
+    // Load patch (memory-mapped when possible)
+    let patch_data = io::load(&patch, &ctx)?;
+
+    // Inspect patch
+    ctx.msg(&format!("Inspecting patch {}", io::filename(&patch)));
+    let info = core::inspect::inspect_with(&patch_data, options)?;
 
     // Show results
     show_inspect_result(&ctx, &patch, &info);
@@ -438,21 +2058,32 @@ fn inspect(patch: PathBuf, level: Level) -> Result<()> {
 fn show_inspect_result(ctx: &Ctx, path: &Path, info: &core::inspect::PatchInfo) {
     use ui::fmt;
 
+    if ctx.format() == OutputFormat::Json {
+        ui::report::print(info);
+        return;
+    }
+
     match ctx.level() {
         Level::Quiet => {}
         Level::Normal => {
-            let checksum_msg = if info.has_checksums {
-                " (with checksums)"
-            } else {
-                ""
+            let checksum_msg = match &info.hash_algorithm {
+                Some(algorithm) => format!(" (with {} checksums)", algorithm),
+                None => String::new(),
             };
-            ctx.done(&format!(
+            let mut msg = format!(
                 "{} Valid {} patch → {} output{}",
                 fmt::ok(),
                 fmt::bytes(info.patch_size),
                 fmt::bytes(info.expected_output_size),
                 checksum_msg
-            ));
+            );
+            if let Some(stats) = &info.control {
+                msg.push_str(&control_summary(stats));
+            }
+            if let Some(regions) = &info.regions {
+                msg.push_str(&region_map(regions));
+            }
+            ctx.done(&msg);
         }
         Level::Verbose => {
             let mut msg = format!(
@@ -471,11 +2102,37 @@ fn show_inspect_result(ctx: &Ctx, path: &Path, info: &core::inspect::PatchInfo)
                 fmt::info(),
                 if info.has_reverse { "yes" } else { "no" }
             );
+            msg.push_str(&format!("\n   {} Codec:         {}", fmt::info(), info.codec));
+            msg.push_str(&format!(
+                "\n   {} Signed by:     {}",
+                fmt::info(),
+                info.signer.as_deref().unwrap_or("unsigned")
+            ));
+
+            if !info.metadata.is_empty() {
+                msg.push_str(&format!("\n   {} Metadata:", fmt::info()));
+                for (key, value) in &info.metadata {
+                    msg.push_str(&format!("\n      {} = {}", key, value));
+                }
+            }
+
+            if let Some(archive) = &info.archive {
+                msg.push_str(&format!("\n   {} Entries:       {}", fmt::info(), archive));
+            }
+
+            if let Some(rom) = &info.rom {
+                msg.push_str(&format!("\n   {} Records:       {}", fmt::info(), rom));
+                if let Some(manifest) = &rom.metadata {
+                    msg.push_str(&format!("\n   {} Manifest:      {}", fmt::info(), manifest));
+                }
+            }
 
             if info.has_checksums {
                 msg.push_str(&format!(
-                    "\n   {} Checksums:     yes\n   {} Base hash:     {}\n   {} Output hash:   {}",
+                    "\n   {} Checksums:     yes\n   {} Algorithm:     {}\n   {} Base hash:     {}\n   {} Output hash:   {}",
+                    fmt::info(),
                     fmt::info(),
+                    info.hash_algorithm.as_deref().unwrap_or("none"),
                     fmt::info(),
                     info.base_checksum.as_ref().unwrap_or(&"none".to_string()),
                     fmt::info(),
@@ -483,7 +2140,95 @@ fn show_inspect_result(ctx: &Ctx, path: &Path, info: &core::inspect::PatchInfo)
                 ));
             }
 
+            if let Some(stats) = &info.control {
+                msg.push_str(&control_summary(stats));
+            }
+            if let Some(regions) = &info.regions {
+                msg.push_str(&region_map(regions));
+            }
+
             ctx.done(&msg);
         }
     }
 }
+
+/// Region map of a patch, one line per region
+fn region_map(regions: &[core::preview::PatchRegion]) -> String {
+    use core::preview::RegionKind;
+    use ui::fmt;
+
+    let mut msg = format!(
+        "\n   {} Regions:       {}",
+        fmt::info(),
+        core::preview::regions_summary(regions)
+    );
+    for region in regions.iter().take(MAX_REGIONS) {
+        let (kind, base) = match (region.kind, region.base_offset) {
+            (RegionKind::Copied, Some(base)) => ("copied", format!("from base 0x{:08x}", base)),
+            (RegionKind::Modified, Some(base)) => ("modified", format!("from base 0x{:08x}", base)),
+            _ => ("inserted", String::new()),
+        };
+        msg.push_str(&format!(
+            "\n      0x{:08x} {:>10} {:<8} {}",
+            region.output_offset,
+            fmt::bytes(region.len),
+            kind,
+            base
+        ));
+    }
+    if regions.len() > MAX_REGIONS {
+        msg.push_str(&format!(
+            "\n   {} ... and {} more region{}",
+            fmt::info(),
+            regions.len() - MAX_REGIONS,
+            if regions.len() - MAX_REGIONS == 1 {
+                ""
+            } else {
+                "s"
+            }
+        ));
+    }
+    msg
+}
+
+/// Control stream statistics with a copy length histogram
+fn control_summary(stats: &core::inspect::ControlStats) -> String {
+    use ui::fmt;
+
+    let mut msg = format!(
+        "\n   {} Controls:      {}\n   {} From base:     {} ({:.1}%)\n   {} Extra bytes:   {}\n   {} Largest seek:  {}",
+        fmt::info(),
+        stats.entries,
+        fmt::info(),
+        fmt::bytes(stats.base_bytes),
+        stats.base_share(),
+        fmt::info(),
+        fmt::bytes(stats.extra_bytes),
+        fmt::info(),
+        stats.largest_seek
+    );
+
+    msg.push_str(&format!("\n   {} Copy lengths:", fmt::info()));
+    let most = stats
+        .copy_lengths
+        .iter()
+        .map(|bucket| bucket.count)
+        .max()
+        .unwrap_or(1);
+    for bucket in &stats.copy_lengths {
+        let range = if bucket.min == bucket.max {
+            bucket.min.to_string()
+        } else {
+            format!("{}-{}", bucket.min, bucket.max)
+        };
+        let width = (bucket.count * HISTOGRAM_WIDTH).div_ceil(most);
+        msg.push_str(&format!(
+            "\n      {:>21} {:>8} {}",
+            range,
+            bucket.count,
+            "█".repeat(width as usize)
+        ));
+    }
+    msg
+}
+
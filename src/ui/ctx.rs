@@ -2,12 +2,13 @@ use std::time::Duration;
 
 use indicatif::{ProgressBar, ProgressStyle};
 
-use super::Level;
+use super::{Level, OutputFormat};
 
 /// Context for UI operations - eliminates spinner duplication
 pub struct Ctx {
     spin: Option<ProgressBar>,
     level: Level,
+    format: OutputFormat,
 }
 
 impl Ctx {
@@ -19,6 +20,25 @@ impl Ctx {
                 Some(make_spin())
             },
             level,
+            format: OutputFormat::Text,
+        }
+    }
+
+    /// Context without a spinner, for runs streaming data to stdout
+    pub fn headless(level: Level) -> Self {
+        Self {
+            spin: None,
+            level,
+            format: OutputFormat::Text,
+        }
+    }
+
+    /// Context printing a JSON report instead of text
+    pub fn json() -> Self {
+        Self {
+            spin: None,
+            level: Level::Quiet,
+            format: OutputFormat::Json,
         }
     }
 
@@ -36,9 +56,29 @@ impl Ctx {
         }
     }
 
+    /// Print a warning above the spinner, unless quiet
+    pub fn warn(&self, text: &str) {
+        match self.spin {
+            Some(ref s) => s.println(format!("{} {}", super::fmt::warn(), text)),
+            None if !self.level.quiet() => eprintln!("{} {}", super::fmt::warn(), text),
+            None => {}
+        }
+    }
+
+    /// Remove the spinner before printing output directly
+    pub fn clear(&self) {
+        if let Some(ref s) = self.spin {
+            s.finish_and_clear();
+        }
+    }
+
     pub fn level(&self) -> Level {
         self.level
     }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
 }
 
 /// Create spinner with standard style
@@ -45,11 +45,36 @@ pub fn ok() -> String {
     "✓".green().bold().to_string()
 }
 
+/// Failure marker (✗ red + bold)
+pub fn fail() -> String {
+    "✗".red().bold().to_string()
+}
+
+/// Skipped marker (- dimmed)
+pub fn skip() -> String {
+    "-".dimmed().to_string()
+}
+
+/// Warning marker (! yellow + bold)
+pub fn warn() -> String {
+    "!".yellow().bold().to_string()
+}
+
 /// Info marker (● blue)
 pub fn info() -> String {
     "●".blue().to_string()
 }
 
+/// Removed bytes (red)
+pub fn removed(text: &str) -> String {
+    format!("{}", text.red())
+}
+
+/// Added bytes (green)
+pub fn added(text: &str) -> String {
+    format!("{}", text.green())
+}
+
 /// Path formatter with subtle styling
 pub fn path(p: impl std::fmt::Display) -> String {
     format!("{}", p.cyan().bold())
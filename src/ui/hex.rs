@@ -0,0 +1,89 @@
+use std::ops::Range;
+
+use super::fmt;
+
+/// Bytes per row on each side
+const ROW: usize = 8;
+/// Width of an empty column: offset plus one cell per byte
+const COLUMN_WIDTH: usize = 8 + ROW * 3;
+
+/// One column of a side-by-side dump
+pub struct Side<'a> {
+    /// Bytes shown, context included
+    pub bytes: &'a [u8],
+    /// Offset of `bytes[0]` in its file
+    pub offset: usize,
+    /// Changed part of `bytes`
+    pub changed: Range<usize>,
+}
+
+impl<'a> Side<'a> {
+    /// `len` changed bytes at `offset` in `data`, with up to `context` bytes around them
+    pub fn around(data: &'a [u8], offset: usize, len: usize, context: usize) -> Self {
+        let start = offset.saturating_sub(context);
+        let end = (offset + len).saturating_add(context).min(data.len());
+        Self {
+            bytes: &data[start..end],
+            offset: start,
+            changed: offset - start..offset - start + len,
+        }
+    }
+}
+
+/// Old and new bytes in two hex columns, changed bytes coloured
+///
+/// With `paired`, changed bytes equal to their counterpart on the other side
+/// stay plain. At most `max_rows` rows are shown.
+pub fn side_by_side(old: &Side, new: &Side, paired: bool, max_rows: usize) -> String {
+    let rows = old.bytes.len().max(new.bytes.len()).div_ceil(ROW);
+
+    let mut out = String::new();
+    for row in 0..rows.min(max_rows) {
+        out.push_str(&format!(
+            "\n      {} │ {}",
+            column(old, new, row * ROW, paired, fmt::removed),
+            column(new, old, row * ROW, paired, fmt::added).trim_end()
+        ));
+    }
+    if rows > max_rows {
+        out.push_str(&format!(
+            "\n      ... {} more row{}",
+            rows - max_rows,
+            if rows - max_rows == 1 { "" } else { "s" }
+        ));
+    }
+    out
+}
+
+fn column(
+    side: &Side,
+    other: &Side,
+    start: usize,
+    paired: bool,
+    paint: fn(&str) -> String,
+) -> String {
+    if start >= side.bytes.len() {
+        return " ".repeat(COLUMN_WIDTH);
+    }
+
+    let mut text = format!("{:08x}", side.offset + start);
+    for i in start..start + ROW {
+        let Some(&byte) = side.bytes.get(i) else {
+            text.push_str("   ");
+            continue;
+        };
+
+        // Compare with the byte at the same distance into the other change
+        let counterpart = i
+            .checked_sub(side.changed.start)
+            .map(|distance| other.changed.start + distance)
+            .filter(|j| other.changed.contains(j))
+            .map(|j| other.bytes[j]);
+        let changed = side.changed.contains(&i) && !(paired && counterpart == Some(byte));
+
+        let cell = format!("{:02x}", byte);
+        text.push(' ');
+        text.push_str(&if changed { paint(&cell) } else { cell });
+    }
+    text
+}
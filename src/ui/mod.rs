@@ -1,6 +1,10 @@
 pub mod ctx;
 pub mod fmt;
+pub mod hex;
 pub mod level;
+pub mod pager;
+pub mod report;
 
 pub use ctx::Ctx;
 pub use level::Level;
+pub use report::OutputFormat;
@@ -0,0 +1,36 @@
+use std::io::{IsTerminal, Write};
+use std::process::{Child, Command, Stdio};
+
+/// Show text through `$PAGER` (`less` by default) when stdout is a terminal
+pub fn page(text: &str) {
+    if std::io::stdout().is_terminal()
+        && let Some(mut child) = spawn()
+    {
+        // The pager may quit before reading everything
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = writeln!(stdin, "{}", text);
+        }
+        let _ = child.wait();
+        return;
+    }
+    println!("{}", text);
+}
+
+fn spawn() -> Option<Child> {
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| "less".to_string());
+    let mut words = pager.split_whitespace();
+
+    // Keep colours, and skip paging when everything fits on screen
+    Command::new(words.next()?)
+        .args(words)
+        .env(
+            "LESS",
+            std::env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+        )
+        .stdin(Stdio::piped())
+        .spawn()
+        .ok()
+}
@@ -0,0 +1,146 @@
+use facet::Facet;
+use miette::Diagnostic;
+
+use crate::{Result, RustineError, RustineErrorKind, core::preview::ByteChange};
+
+/// How command results are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Coloured, human-readable text
+    #[default]
+    Text,
+    /// One JSON document on stdout
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = RustineError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(RustineErrorKind::InvalidArguments {
+                details: format!("unknown output format `{}`, expected text or json", s),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Result of `generate`
+#[derive(Facet, Debug)]
+pub struct GenerateReport {
+    pub output: String,
+    pub original_size: u64,
+    pub patch_size: u64,
+    /// Size saved by the patch, in percent of the original
+    pub reduction: f64,
+    pub bidirectional: bool,
+    pub input_mode: String,
+}
+
+/// Result of `apply`
+#[derive(Facet, Debug)]
+pub struct ApplyReport<'a> {
+    /// Written file, `None` for dry runs
+    pub output: Option<String>,
+    pub base_size: u64,
+    pub patch_size: u64,
+    pub output_size: u64,
+    pub dry_run: bool,
+    pub input_mode: String,
+    pub changes: &'a [ByteChange],
+}
+
+/// Result of `diff`
+#[derive(Facet, Debug)]
+pub struct DiffReport<'a> {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub changes: &'a [ByteChange],
+}
+
+/// Result of `compose`
+#[derive(Facet, Debug)]
+pub struct ComposeReport {
+    pub output: String,
+    pub patches: usize,
+    /// Combined size of the input patches
+    pub input_size: u64,
+    pub patch_size: u64,
+    /// Whether the output was diffed from the base instead of merging control streams
+    pub rebuilt: bool,
+    pub bidirectional: bool,
+    /// Parts of the chain dropped from the composed patch
+    pub lost: Vec<String>,
+}
+
+/// Result of `upgrade`
+#[derive(Facet, Debug)]
+pub struct UpgradeReport {
+    /// Written file, `None` for dry runs and a base already at the target
+    pub output: Option<String>,
+    pub base_size: u64,
+    /// Size of the upgraded file, `None` when nothing was written
+    pub output_size: Option<u64>,
+    /// Total payload size of the chain
+    pub patch_size: u64,
+    pub dry_run: bool,
+    pub steps: Vec<UpgradeStep>,
+}
+
+/// One patch of an upgrade chain
+#[derive(Facet, Debug)]
+pub struct UpgradeStep {
+    pub patch: String,
+    pub reverse: bool,
+    pub size: u64,
+}
+
+/// Result of `convert`
+#[derive(Facet, Debug)]
+pub struct ConvertReport {
+    pub output: String,
+    pub from: String,
+    pub to: String,
+    pub input_size: u64,
+    pub patch_size: u64,
+    /// Features of the input dropped by the conversion
+    pub lost: Vec<String>,
+}
+
+/// Result of `keygen`
+#[derive(Facet, Debug)]
+pub struct KeygenReport {
+    pub secret_key: String,
+    pub public_key: String,
+    pub fingerprint: String,
+}
+
+/// Failure of any command
+#[derive(Facet, Debug)]
+pub struct ErrorReport {
+    /// Stable diagnostic code, e.g. `rustine::checksum_mismatch`
+    pub code: Option<String>,
+    /// Process exit code, see [`RustineErrorKind::exit_code`]
+    pub exit_code: u8,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl From<&RustineError> for ErrorReport {
+    fn from(err: &RustineError) -> Self {
+        Self {
+            code: err.code().map(|code| code.to_string()),
+            exit_code: err.kind.exit_code(),
+            message: err.to_string(),
+            help: err.help().map(|help| help.to_string()),
+        }
+    }
+}
+
+/// Print a report as JSON on stdout
+pub fn print<'a, T: Facet<'a>>(report: &T) {
+    println!("{}", facet_json::to_string(report));
+}